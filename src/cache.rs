@@ -0,0 +1,288 @@
+// savefile-derive puts the impls it generates inside named consts,
+// which this lint reports for every type deriving `Savefile`
+#![allow(non_local_definitions)]
+
+use savefile::prelude::*;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, Metadata};
+use std::hash::{Hash, Hasher};
+use std::{env, io, process};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::git::GitDirKind;
+use crate::lang::Project;
+use crate::ssh_config::expand_tilde;
+
+/// Name of the cache older versions kept in every root.
+const LEGACY_CACHE_FILE: &str = ".codecache";
+/// Bumped whenever the layout of `Cache`, or of `Project` which it
+/// contains, changes. Caches of other versions are rebuilt.
+const CACHE_VERSION: u32 = 2;
+
+/// Leading bytes of every file written by `write`.
+const MAGIC: &[u8; 8] = b"rgscache";
+/// Length of the magic, version, payload length and checksum.
+const HEADER_LEN: usize = 28;
+
+/// Discovery results of a single root. Every entry carries what it was
+/// derived from so that only the parts of the tree that changed since the
+/// last run are searched again.
+#[derive(Savefile, Default)]
+pub struct Cache {
+    /// Root the cache belongs to, guards against clashing file names.
+    pub root: String,
+    /// Whether submodules were listed, entries are not reused otherwise.
+    pub submodules: bool,
+    /// Directories that are not repositories keyed by path.
+    pub dirs: HashMap<String, CachedDir>,
+    /// Repositories and worktrees keyed by path.
+    pub repos: HashMap<String, CachedRepo>,
+}
+
+#[derive(Savefile, Clone)]
+pub struct CachedDir {
+    pub mtime: u64,
+    /// Fingerprint of the `.codeignore` rules `subdirs` were filtered with.
+    pub rules: u64,
+    /// Subdirectories that are not ignored, `None` if the directory hasn't
+    /// been listed because of the search depth.
+    pub subdirs: Option<Vec<String>>,
+}
+
+#[derive(Savefile, Clone)]
+pub struct CachedRepo {
+    /// See `repo_stamp`.
+    pub stamp: u64,
+    pub kind: GitDirKind,
+    /// The repository followed by its worktrees and submodules. Empty for
+    /// worktrees that are listed with their main repository.
+    pub projs: Vec<Project>,
+}
+
+impl Cache {
+    pub fn new(root: &str, submodules: bool) -> Cache {
+        Cache { root: String::from(root), submodules, ..Default::default() }
+    }
+
+    /// Reads the cache of `root`. A missing, outdated or corrupt cache is
+    /// treated as empty so that it is rebuilt.
+    pub fn load(root: &str, submodules: bool) -> Cache {
+        Cache::load_from(&cache_path(root), root, submodules)
+    }
+
+    fn load_from(path: &Path, root: &str, submodules: bool) -> Cache {
+        let cache: Cache = match read(path, CACHE_VERSION) {
+            Ok(cache) => cache,
+            Err(ReadError::Corrupt(err)) => {
+                eprintln!("cgs: rebuilding corrupt cache {}: {}", path.display(), err);
+                return Cache::new(root, submodules);
+            }
+            Err(_) => return Cache::new(root, submodules),
+        };
+        if cache.root != root || cache.submodules != submodules {
+            return Cache::new(root, submodules);
+        }
+        cache
+    }
+
+    pub fn save(&self) -> Result<(), SavefileError> {
+        // the cache used to be kept in the root itself
+        let legacy = Path::new(&self.root).join(LEGACY_CACHE_FILE);
+        if legacy.is_file() {
+            fs::remove_file(legacy);
+        }
+        write(&cache_path(&self.root), CACHE_VERSION, self)
+    }
+
+    /// Returns the entry of a directory that is not a repository if its
+    /// modification time still matches.
+    pub fn dir(&self, path: &str, mtime: u64) -> Option<&CachedDir> {
+        self.dirs.get(path).filter(|dir| dir.mtime == mtime)
+    }
+
+    /// Returns the entry of a repository if its stamp still matches.
+    pub fn repo(&self, path: &str) -> Option<&CachedRepo> {
+        self.repos.get(path).filter(|repo| repo.stamp == repo_stamp(Path::new(path)))
+    }
+}
+
+/// Directory for what rgs keeps between runs, `$XDG_CACHE_HOME/rgs` or
+/// `~/.cache/rgs`.
+pub fn cache_dir() -> PathBuf {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        // relative paths are invalid according to the spec
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| expand_tilde("~/.cache"));
+    base.join("rgs")
+}
+
+/// Cache file of `root`, named after the root's directory and a hash of
+/// its path, e.g. `~/.cache/rgs/roots/src-3c9a0e41b2d07f15`.
+pub fn cache_path(root: &str) -> PathBuf {
+    let name = Path::new(root).file_name().and_then(|name| name.to_str()).unwrap_or("root");
+    cache_dir().join("roots").join(format!("{}-{:016x}", name, checksum(root.as_bytes())))
+}
+
+/// Why a file written by `write` couldn't be read.
+pub enum ReadError {
+    Missing,
+    /// Written by another version of rgs.
+    Incompatible,
+    Corrupt(String),
+}
+
+/// Writes `data` behind a header holding its version, length and checksum.
+/// The file is replaced atomically so that an interrupted run never leaves
+/// a truncated file behind.
+pub fn write<T: WithSchema + Serialize>(path: &Path, version: u32, data: &T) -> Result<(), SavefileError> {
+    let payload = save_to_mem(version, data)?;
+    let mut content = Vec::with_capacity(HEADER_LEN + payload.len());
+    content.extend_from_slice(MAGIC);
+    content.extend_from_slice(&version.to_le_bytes());
+    content.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    content.extend_from_slice(&checksum(&payload).to_le_bytes());
+    content.extend_from_slice(&payload);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!("tmp{}", process::id()));
+    if let Err(err) = fs::write(&tmp, &content).and_then(|_| fs::rename(&tmp, path)) {
+        fs::remove_file(&tmp);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Reads a file written by `write`, checking it before it is deserialized.
+pub fn read<T: WithSchema + Deserialize>(path: &Path, version: u32) -> Result<T, ReadError> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(ReadError::Missing),
+        Err(err) => return Err(ReadError::Corrupt(err.to_string())),
+    };
+    if !content.starts_with(MAGIC) {
+        return Err(ReadError::Incompatible);
+    }
+    if content.len() < HEADER_LEN {
+        return Err(ReadError::Corrupt(String::from("truncated header")));
+    }
+
+    let field = |start: usize, len: usize| {
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(&content[start..start + len]);
+        u64::from_le_bytes(bytes)
+    };
+    if field(8, 4) != version as u64 {
+        return Err(ReadError::Incompatible);
+    }
+    let payload = &content[HEADER_LEN..];
+    if field(12, 8) != payload.len() as u64 {
+        return Err(ReadError::Corrupt(String::from("truncated")));
+    }
+    if field(20, 8) != checksum(payload) {
+        return Err(ReadError::Corrupt(String::from("checksum mismatch")));
+    }
+
+    load_from_mem(payload, version).map_err(|err| match err {
+        SavefileError::IncompatibleSchema { .. } | SavefileError::WrongVersion { .. } => ReadError::Incompatible,
+        err => ReadError::Corrupt(err.to_string()),
+    })
+}
+
+/// 64-bit FNV-1a, unlike `DefaultHasher` it is the same across Rust
+/// versions.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Modification time of a file in nanoseconds.
+pub fn mtime(meta: &Metadata) -> u64 {
+    meta.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Combines what decides how a repository is listed: the type of its `.git`
+/// entry and the modification times of the places git records worktrees and
+/// submodules in. The directory's own modification time isn't used as it
+/// changes with every file added to the working tree.
+pub fn repo_stamp(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let dot_git = fs::metadata(path.join(".git")).ok();
+    dot_git.as_ref().map(Metadata::is_dir).hash(&mut hasher);
+    for file in [".git/worktrees", ".git/modules", ".gitmodules", "HEAD", "worktrees"] {
+        fs::metadata(path.join(file)).ok().as_ref().map(mtime).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "/src";
+
+    fn cache() -> Cache {
+        let mut cache = Cache::new(ROOT, false);
+        cache.dirs.insert(String::from("/src/a"), CachedDir { mtime: 1, rules: 2, subdirs: Some(vec![String::from("b")]) });
+        cache
+    }
+
+    /// Writes a cache, lets `damage` modify the file and loads it again.
+    fn reload(name: &str, damage: impl FnOnce(&mut Vec<u8>)) -> Cache {
+        let path = env::temp_dir().join(format!("rgs-cache-{}-{}", process::id(), name));
+        write(&path, CACHE_VERSION, &cache()).unwrap();
+        let mut content = fs::read(&path).unwrap();
+        damage(&mut content);
+        fs::write(&path, content).unwrap();
+        let cache = Cache::load_from(&path, ROOT, false);
+        fs::remove_file(&path).unwrap();
+        cache
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let cache = reload("ok", |_| {});
+        assert_eq!(cache.root, ROOT);
+        let dir = cache.dir("/src/a", 1).unwrap();
+        assert_eq!((dir.rules, dir.subdirs.as_deref()), (2, Some(&[String::from("b")][..])));
+        assert!(cache.dir("/src/a", 3).is_none());
+    }
+
+    #[test]
+    fn wrong_magic_is_rescanned() {
+        assert!(reload("magic", |content| content[0] = b'x').dirs.is_empty());
+    }
+
+    #[test]
+    fn wrong_version_is_rescanned() {
+        let cache = reload("version", |content| content[8..12].copy_from_slice(&(CACHE_VERSION + 1).to_le_bytes()));
+        assert!(cache.dirs.is_empty());
+    }
+
+    #[test]
+    fn truncated_file_is_rescanned() {
+        assert!(reload("truncated", |content| content.truncate(content.len() - 1)).dirs.is_empty());
+        assert!(reload("truncated-header", |content| content.truncate(HEADER_LEN - 1)).dirs.is_empty());
+    }
+
+    #[test]
+    fn checksum_mismatch_is_rescanned() {
+        assert!(reload("checksum", |content| *content.last_mut().unwrap() ^= 1).dirs.is_empty());
+    }
+
+    #[test]
+    fn other_root_is_rescanned() {
+        let path = env::temp_dir().join(format!("rgs-cache-{}-root", process::id()));
+        write(&path, CACHE_VERSION, &cache()).unwrap();
+        let cache = Cache::load_from(&path, "/other", false);
+        fs::remove_file(&path).unwrap();
+        assert!(cache.dirs.is_empty());
+        assert_eq!(cache.root, "/other");
+    }
+}
@@ -0,0 +1,294 @@
+use colored::*;
+use glob::{MatchOptions, Pattern};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+pub const CODEIGNORE: &str = ".codeignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A single `.codeignore` pattern.
+#[derive(Clone)]
+struct Rule {
+    /// Directory of the `.codeignore` file the rule comes from, paths are
+    /// matched relative to it.
+    base: PathBuf,
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    /// Patterns containing a slash match the whole relative path, others
+    /// only the file name.
+    anchored: bool,
+}
+
+/// Rules of all `.codeignore` files that apply to the directory being
+/// scanned, following `.gitignore` semantics: the last matching rule wins
+/// and rules of nested files come after those of their parents.
+#[derive(Clone, Default)]
+pub struct CodeIgnore {
+    rules: Vec<Rule>,
+}
+
+impl CodeIgnore {
+    /// Reads the `.codeignore` file of `dir` if there is one. Returns the
+    /// previous number of rules which `truncate` restores once `dir` is left.
+    pub fn load(&mut self, dir: &Path) -> usize {
+        let len = self.rules.len();
+        let file = dir.join(CODEIGNORE);
+        if let Ok(content) = fs::read_to_string(&file) {
+            self.rules.extend(parse(&content, dir, &file));
+        }
+        len
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.rules.truncate(len);
+    }
+
+    /// Identifies the current set of rules, used to tell whether a cached
+    /// directory listing was filtered by the same rules.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for rule in &self.rules {
+            rule.base.hash(&mut hasher);
+            rule.pattern.as_str().hash(&mut hasher);
+            (rule.negated, rule.dir_only, rule.anchored).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for rule in self.rules.iter().rev() {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let relative = match path.strip_prefix(&rule.base) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            let matched = if rule.anchored {
+                relative.to_str().is_some_and(|relative| rule.pattern.matches_with(relative, MATCH_OPTIONS))
+            } else {
+                relative.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| rule.pattern.matches_with(name, MATCH_OPTIONS))
+            };
+            if matched {
+                return !rule.negated;
+            }
+        }
+        false
+    }
+}
+
+/// Parses the content of a `.codeignore` file. Invalid patterns are reported
+/// with their file and line number and skipped.
+fn parse(content: &str, base: &Path, file: &Path) -> Vec<Rule> {
+    let mut rules = vec![];
+    for (i, line) in content.lines().enumerate() {
+        match parse_line(line, base) {
+            Ok(Some(rule)) => rules.push(rule),
+            Ok(None) => {}
+            Err(err) => {
+                let msg = format!("cgs: {}:{}: invalid pattern '{}': {}", file.display(), i + 1, line, err);
+                eprintln!("{}", msg.red());
+            }
+        }
+    }
+    rules
+}
+
+fn parse_line(line: &str, base: &Path) -> Result<Option<Rule>, String> {
+    let line = trim_trailing_spaces(line);
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (negated, line) = match line.strip_prefix('!') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    // `\!` and `\#` escape a literal leading character
+    let line = line.strip_prefix('\\').filter(|l| l.starts_with(['!', '#'])).unwrap_or(line);
+
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(line) if !line.ends_with('\\') => (true, line),
+        _ => (false, line),
+    };
+    let anchored = line.contains('/');
+    let line = line.strip_prefix('/').unwrap_or(line);
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let pattern = Pattern::new(&escape(line)?).map_err(|err| String::from(err.msg))?;
+    Ok(Some(Rule { base: base.to_path_buf(), pattern, negated, dir_only, anchored }))
+}
+
+/// Removes trailing spaces unless they are escaped with a backslash.
+fn trim_trailing_spaces(line: &str) -> &str {
+    let mut end = line.len();
+    while line[..end].ends_with(' ') && !line[..end - 1].ends_with('\\') {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+/// Converts backslash escapes, which `glob::Pattern` doesn't know, into
+/// single character classes.
+fn escape(pattern: &str) -> Result<String, String> {
+    let mut escaped = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            escaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(c @ ('*' | '?' | '[' | ']')) => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            }
+            Some(c) => escaped.push(c),
+            None => return Err(String::from("trailing backslash")),
+        }
+    }
+    Ok(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(content: &str) -> CodeIgnore {
+        CodeIgnore { rules: parse(content, Path::new("/code"), Path::new("/code/.codeignore")) }
+    }
+
+    fn ignored(codeignore: &CodeIgnore, path: &str, is_dir: bool) -> bool {
+        codeignore.is_ignored(&Path::new("/code").join(path), is_dir)
+    }
+
+    #[test]
+    fn trailing_spaces() {
+        assert_eq!(trim_trailing_spaces("target  "), "target");
+        assert_eq!(trim_trailing_spaces("target\\ "), "target\\ ");
+        assert_eq!(trim_trailing_spaces("target\\  "), "target\\ ");
+        assert_eq!(trim_trailing_spaces("   "), "");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape("a\\*b").unwrap(), "a[*]b");
+        assert_eq!(escape("\\?\\[\\]").unwrap(), "[?][[][]]");
+        assert_eq!(escape("\\ a\\b").unwrap(), " ab");
+        assert!(escape("a\\").is_err());
+    }
+
+    #[test]
+    fn skipped_lines() {
+        let base = Path::new("/code");
+        assert!(parse_line("", base).unwrap().is_none());
+        assert!(parse_line("# comment", base).unwrap().is_none());
+        assert!(parse_line("/", base).unwrap().is_none());
+        assert!(parse_line("!", base).unwrap().is_none());
+        assert!(parse_line("a\\", base).is_err());
+    }
+
+    #[test]
+    fn parsed_flags() {
+        let base = Path::new("/code");
+        let rule = parse_line("!/build/", base).unwrap().unwrap();
+        assert!(rule.negated && rule.dir_only && rule.anchored);
+        assert_eq!(rule.pattern.as_str(), "build");
+
+        let rule = parse_line("out", base).unwrap().unwrap();
+        assert!(!rule.negated && !rule.dir_only && !rule.anchored);
+
+        let rule = parse_line("a/b", base).unwrap().unwrap();
+        assert!(rule.anchored);
+
+        let rule = parse_line("\\!important", base).unwrap().unwrap();
+        assert!(!rule.negated);
+        assert_eq!(rule.pattern.as_str(), "!important");
+
+        let rule = parse_line("\\#hash", base).unwrap().unwrap();
+        assert_eq!(rule.pattern.as_str(), "#hash");
+    }
+
+    #[test]
+    fn names_match_at_any_level() {
+        let codeignore = rules("node_modules\n*.tmp\n");
+        assert!(ignored(&codeignore, "node_modules", true));
+        assert!(ignored(&codeignore, "web/app/node_modules", true));
+        assert!(ignored(&codeignore, "a/b.tmp", false));
+        assert!(!ignored(&codeignore, "node_modules_old", true));
+    }
+
+    #[test]
+    fn anchoring() {
+        let codeignore = rules("/tmp\nsrc/gen\n");
+        assert!(ignored(&codeignore, "tmp", true));
+        assert!(!ignored(&codeignore, "a/tmp", true));
+        assert!(ignored(&codeignore, "src/gen", true));
+        assert!(!ignored(&codeignore, "a/src/gen", true));
+        // paths outside of the `.codeignore` directory are never matched
+        assert!(!codeignore.is_ignored(Path::new("/other/tmp"), true));
+    }
+
+    #[test]
+    fn wildcards() {
+        let codeignore = rules("a/*/c\n**/out\nbuild/**\n");
+        assert!(ignored(&codeignore, "a/b/c", true));
+        assert!(!ignored(&codeignore, "a/b/b/c", true));
+        assert!(ignored(&codeignore, "out", true));
+        assert!(ignored(&codeignore, "x/y/out", true));
+        assert!(ignored(&codeignore, "build/x/y", true));
+    }
+
+    #[test]
+    fn dir_only() {
+        let codeignore = rules("cache/\n");
+        assert!(ignored(&codeignore, "cache", true));
+        assert!(ignored(&codeignore, "a/cache", true));
+        assert!(!ignored(&codeignore, "cache", false));
+    }
+
+    #[test]
+    fn negation() {
+        let codeignore = rules("/var/*\n!/var/neovim\n");
+        assert!(ignored(&codeignore, "var/vim", true));
+        assert!(!ignored(&codeignore, "var/neovim", true));
+        assert!(!ignored(&codeignore, "var", true));
+
+        // the last matching rule wins
+        let codeignore = rules("!/var/neovim\n/var/*\n");
+        assert!(ignored(&codeignore, "var/neovim", true));
+    }
+
+    #[test]
+    fn negation_under_ignored_parent() {
+        // the parent is ignored, so the walk never reaches the negated path
+        let codeignore = rules("/var\n!/var/neovim\n");
+        assert!(ignored(&codeignore, "var", true));
+    }
+
+    #[test]
+    fn nested_rules_override() {
+        let mut codeignore = rules("build\n");
+        codeignore.rules.extend(parse("!build\n", Path::new("/code/app"), Path::new("/code/app/.codeignore")));
+        assert!(ignored(&codeignore, "build", true));
+        assert!(!ignored(&codeignore, "app/build", true));
+
+        let fingerprint = codeignore.fingerprint();
+        codeignore.truncate(1);
+        assert_ne!(codeignore.fingerprint(), fingerprint);
+        assert!(ignored(&codeignore, "app/build", true));
+    }
+}
@@ -0,0 +1,276 @@
+use serde::de::{self, Deserializer};
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs};
+
+use crate::git::UntrackedMode;
+use crate::print::{OutputFormat, SortType, StatusCategory};
+use crate::ssh_config::expand_tilde;
+
+/// Settings of a configuration file, either its top-level defaults or a
+/// profile. Every key mirrors the long name of a command line option.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub code: Option<Code>,
+    pub print_code: Option<bool>,
+    pub verbose: Option<u8>,
+    pub no_ignore: Option<bool>,
+    #[serde(deserialize_with = "parse_sort")]
+    pub sort: Option<SortType>,
+    #[serde(deserialize_with = "parse_list")]
+    pub filter: Option<Vec<StatusCategory>>,
+    #[serde(deserialize_with = "parse")]
+    pub untracked: Option<UntrackedMode>,
+    pub stash_dirty: Option<bool>,
+    #[serde(deserialize_with = "parse")]
+    pub format: Option<OutputFormat>,
+    pub fetch: Option<bool>,
+    pub prune: Option<bool>,
+    pub tags: Option<bool>,
+    pub no_tags: Option<bool>,
+    pub fetch_timeout: Option<u64>,
+    pub fetch_retries: Option<u32>,
+    #[serde(rename = "ff", alias = "fast-forward")]
+    pub fast_forward: Option<bool>,
+    #[serde(rename = "ff-all", alias = "fast-forward-all")]
+    pub fast_forward_all: Option<bool>,
+    pub push: Option<bool>,
+    pub dry_run: Option<bool>,
+    pub stale: Option<bool>,
+    pub prune_merged: Option<bool>,
+    pub depth: Option<usize>,
+    pub submodules: Option<bool>,
+    pub refresh_cache: Option<bool>,
+    pub no_cache: Option<bool>,
+    pub jobs: Option<usize>,
+    pub time: Option<bool>,
+    pub all: Option<bool>,
+    pub dir: Option<bool>,
+    #[serde(rename = "mod", alias = "modification")]
+    pub modification: Option<bool>,
+    pub diff: Option<bool>,
+    pub check: Option<bool>,
+    pub branches: Option<bool>,
+    pub watch: Option<Vec<String>>,
+    pub timeout: Option<u64>,
+    pub exit: Option<bool>,
+    pub notify: Option<bool>,
+    /// Only allowed at the top level of the file.
+    pub profiles: Option<BTreeMap<String, Settings>>,
+}
+
+/// `code` is either a single, possibly colon-separated, root or a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Code {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Code {
+    pub fn roots(&self) -> Vec<String> {
+        match self {
+            Code::One(code) => code.split(':').map(String::from).collect(),
+            Code::Many(roots) => roots.clone(),
+        }
+    }
+}
+
+/// A configuration file that has been read along with its path.
+pub struct Config {
+    pub path: PathBuf,
+    pub defaults: Settings,
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+impl Config {
+    /// Reads `$XDG_CONFIG_HOME/rgs/config.toml`, falling back to the older
+    /// `~/.coderc` and `~/.config/coderc`. `None` if there is none. Only the
+    /// selected `profile` of the older files is read.
+    pub fn load(profile: Option<&str>) -> Result<Option<Config>, String> {
+        let path = config_path();
+        if path.exists() {
+            let content = read(&path)?;
+            let mut defaults: Settings = toml::from_str(&content).map_err(|err| describe(&path, &content, err))?;
+            let profiles = defaults.profiles.take().unwrap_or_default();
+            if let Some((name, _)) = profiles.iter().find(|(_, profile)| profile.profiles.is_some()) {
+                return Err(format!("{}: profiles.{}: profiles can't be nested", path.display(), name));
+            }
+            return Ok(Some(Config { path, defaults, profiles }));
+        }
+
+        // every table of the older files is a profile, there are no defaults
+        for path in [expand_tilde("~/.coderc"), expand_tilde("~/.config/coderc")] {
+            if path.exists() {
+                let mut profiles = BTreeMap::new();
+                if let Some(name) = profile {
+                    let content = read(&path)?;
+                    if let Some(settings) = legacy_profile(&path, &content, name)? {
+                        profiles.insert(String::from(name), settings);
+                    }
+                }
+                return Ok(Some(Config { path, defaults: Settings::default(), profiles }));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Settings, String> {
+        self.profiles.get(name)
+            .ok_or_else(|| format!("{}: profile '{}' not found", self.path.display(), name))
+    }
+}
+
+/// Reads the `name` table of an older configuration file. These files used
+/// to be read without any checks, so unknown keys and invalid values are
+/// reported and ignored instead of failing the run.
+fn legacy_profile(path: &Path, content: &str, name: &str) -> Result<Option<Settings>, String> {
+    let mut table: toml::Table = toml::from_str(content).map_err(|err| describe(path, content, err))?;
+    let profile = match table.remove(name) {
+        Some(toml::Value::Table(profile)) => profile,
+        Some(_) => return Err(format!("{}: {}: profile is not a table", path.display(), name)),
+        None => return Ok(None),
+    };
+
+    // keys are added one at a time so that each error is tied to its key,
+    // including a key set twice through an alias
+    let mut valid = toml::Table::new();
+    let mut settings = Settings::default();
+    for (key, value) in profile {
+        let error = if !KEYS.contains(&key.as_str()) {
+            String::from("unknown key")
+        } else {
+            let mut candidate = valid.clone();
+            candidate.insert(key.clone(), value);
+            match toml::Value::Table(candidate.clone()).try_into() {
+                Ok(parsed) => {
+                    settings = parsed;
+                    valid = candidate;
+                    continue;
+                }
+                Err(err) => String::from(err.message()),
+            }
+        };
+        eprintln!("rgs: {}: {}.{}: {}, ignored", path.display(), name, key, error);
+    }
+    Ok(Some(settings))
+}
+
+/// Keys accepted by `Settings` in a profile, aliases included.
+const KEYS: &[&str] = &[
+    "code", "print-code", "verbose", "no-ignore", "sort", "filter", "untracked", "stash-dirty", "format",
+    "fetch", "prune", "tags", "no-tags", "fetch-timeout", "fetch-retries",
+    "ff", "fast-forward", "ff-all", "fast-forward-all", "push", "dry-run", "stale", "prune-merged",
+    "depth", "submodules", "refresh-cache", "no-cache", "jobs", "time", "all", "dir", "mod", "modification",
+    "diff", "check", "branches", "watch", "timeout", "exit", "notify",
+];
+
+fn config_path() -> PathBuf {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        // relative paths are invalid according to the spec
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| expand_tilde("~/.config"));
+    base.join("rgs").join("config.toml")
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// Formats a parse error as `file:line: key: message`, the key being the
+/// full dotted key the error is about.
+fn describe(path: &Path, content: &str, err: toml::de::Error) -> String {
+    let start = match err.span() {
+        Some(span) => span.start,
+        None => return format!("{}: {}", path.display(), err.message()),
+    };
+    let line = content[..start].matches('\n').count() + 1;
+    let text = content.lines().nth(line - 1).unwrap_or_default();
+    let table = content[..start].lines().rev()
+        .map(str::trim)
+        .find(|l| l.starts_with('[') && l.ends_with(']'))
+        .map(|l| l.trim_matches(|c| c == '[' || c == ']').trim());
+    let key = text.split_once('=').map(|(key, _)| key.trim()).filter(|key| !key.is_empty());
+    match (table, key) {
+        (Some(table), Some(key)) => format!("{}:{}: {}.{}: {}", path.display(), line, table, key, err.message()),
+        (None, Some(key)) => format!("{}:{}: {}: {}", path.display(), line, key, err.message()),
+        _ => format!("{}:{}: {}", path.display(), line, err.message()),
+    }
+}
+
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where D: Deserializer<'de>, T: FromStr, T::Err: Display {
+    let value = <Option<String> as serde::Deserialize>::deserialize(deserializer)?;
+    value.map(|value| value.parse().map_err(de::Error::custom)).transpose()
+}
+
+fn parse_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+    where D: Deserializer<'de>, T: FromStr, T::Err: Display {
+    let values = <Option<Vec<String>> as serde::Deserialize>::deserialize(deserializer)?;
+    values.map(|values| values.iter().map(|value| value.parse().map_err(de::Error::custom)).collect()).transpose()
+}
+
+/// Unlike on the command line an unknown sort order is an error.
+fn parse_sort<'de, D>(deserializer: D) -> Result<Option<SortType>, D::Error>
+    where D: Deserializer<'de> {
+    let value = <Option<String> as serde::Deserialize>::deserialize(deserializer)?;
+    value.map(|value| match SortType::from(value.as_str()) {
+        SortType::None if value != "none" => Err(de::Error::custom(format!("invalid sort order '{}'", value))),
+        sort => Ok(sort),
+    }).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy(content: &str, name: &str) -> Result<Option<Settings>, String> {
+        legacy_profile(Path::new("coderc"), content, name)
+    }
+
+    #[test]
+    fn keys_match_settings() {
+        // the error for an unknown key lists every key serde accepts
+        let err = toml::from_str::<Settings>("unknown = 1").err().unwrap();
+        let expected = err.message().split("expected one of ").nth(1).unwrap();
+        let mut accepted = expected.split(", ").map(|key| key.trim_matches('`')).collect::<Vec<_>>();
+        let mut keys = KEYS.iter().copied().chain(std::iter::once("profiles")).collect::<Vec<_>>();
+        accepted.sort_unstable();
+        keys.sort_unstable();
+        assert_eq!(accepted, keys);
+    }
+
+    #[test]
+    fn legacy_reads_selected_profile() {
+        let content = "[work]\ncode = \"/work\"\ndepth = 3\n[broken]\nsort = \"bogus\"\n";
+        let settings = legacy(content, "work").unwrap().unwrap();
+        assert_eq!(settings.code.map(|code| code.roots()), Some(vec![String::from("/work")]));
+        assert_eq!(settings.depth, Some(3));
+        assert!(legacy(content, "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn legacy_ignores_unknown_keys_and_invalid_values() {
+        let content = "[work]\ncolour = true\nsort = \"bogus\"\ndepth = \"3\"\nprofiles = {}\nall = true\n";
+        let settings = legacy(content, "work").unwrap().unwrap();
+        assert_eq!(settings.all, Some(true));
+        assert!(settings.sort.is_none() && settings.depth.is_none() && settings.profiles.is_none());
+    }
+
+    #[test]
+    fn legacy_ignores_repeated_aliases() {
+        let settings = legacy("[work]\nmod = false\nmodification = true\n", "work").unwrap().unwrap();
+        assert_eq!(settings.modification, Some(false));
+    }
+
+    #[test]
+    fn legacy_errors() {
+        assert!(legacy("[work\n", "work").is_err());
+        assert!(legacy("work = 1\n", "work").is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use git2::{Config, Cred, CredentialHelper, CredentialType, Error, Repository};
+use http::Uri;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::{env, fs};
+#[cfg(unix)]
+use crate::{askpass, progress};
+use crate::ssh_config::{self, SshHost, SshUrl, expand_tilde};
+
+/// HTTPS credentials resolved during this run keyed by host. Shared by all
+/// fetch jobs so that credential helpers run, and possibly prompt, only once
+/// per host.
+static HTTPS_CACHE: Mutex<BTreeMap<String, (String, String)>> = Mutex::new(BTreeMap::new());
+
+/// Held while a credential helper runs. Helpers may prompt, so only one runs
+/// at a time and jobs waiting for one find its result in `HTTPS_CACHE`.
+static HELPER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Runs a credential helper with the live display removed, as the helper
+/// may prompt on the terminal, and without the time it takes counting
+/// against fetch timeouts.
+#[cfg(unix)]
+fn run_helper<T>(f: impl FnOnce() -> T) -> T {
+    progress::suspend_display(f)
+}
+
+#[cfg(not(unix))]
+fn run_helper<T>(f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+/// Sources of HTTPS credentials in the order they are tried.
+#[derive(Clone, Copy, PartialEq)]
+enum HttpsSource {
+    Cache,
+    Env,
+    Netrc,
+    Helper,
+}
+
+const HTTPS_SOURCES: [HttpsSource; 4] = [HttpsSource::Cache, HttpsSource::Env, HttpsSource::Netrc, HttpsSource::Helper];
+
+/// SSH identities in the order they are offered, following OpenSSH: keys
+/// from the agent unless `IdentitiesOnly` is set, then the identity files.
+enum SshIdentity {
+    Agent,
+    File(PathBuf),
+}
+
+/// Credential state of a single network operation. libgit2 invokes the
+/// credentials callback again whenever the returned credentials are
+/// rejected, so every call moves on to the next source until none are left.
+pub struct Credentials {
+    config: Option<Config>,
+    /// Git directory credential helpers are run in.
+    git_dir: PathBuf,
+    url: String,
+    https_tried: usize,
+    https_last: Option<(String, String, HttpsSource)>,
+    /// ssh_config of the host as written in the remote URL, which may be an
+    /// alias of the host libgit2 actually connects to.
+    ssh_host: Option<SshHost>,
+    ssh_identities: Option<Vec<SshIdentity>>,
+}
+
+impl Credentials {
+    pub fn new(repo: &Repository, url: &str) -> Self {
+        Credentials {
+            config: repo.config().ok(),
+            git_dir: repo.path().to_path_buf(),
+            url: String::from(url),
+            https_tried: 0,
+            https_last: None,
+            ssh_host: SshUrl::parse(url).map(|url| ssh_config::query(&url.host)),
+            ssh_identities: None,
+        }
+    }
+
+    pub fn resolve(&mut self, url: &str, username_from_url: Option<&str>, allowed_types: CredentialType) -> Result<Cred, Error> {
+        if allowed_types.contains(CredentialType::USERNAME) {
+            Cred::username(&self.ssh_user(username_from_url))
+        } else if allowed_types.contains(CredentialType::SSH_KEY) {
+            self.ssh_credentials(url, username_from_url)
+        } else if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            self.https_credentials(url, username_from_url)
+        } else {
+            Cred::default()
+        }
+    }
+
+    /// User from the URL, then the `User` option, then the local user.
+    fn ssh_user(&self, username_from_url: Option<&str>) -> String {
+        username_from_url.map(String::from)
+            .or_else(|| self.ssh_host.as_ref().and_then(|host| host.user.clone()))
+            .unwrap_or_else(ssh_config::local_user)
+    }
+
+    fn ssh_credentials(&mut self, url: &str, username_from_url: Option<&str>) -> Result<Cred, Error> {
+        let user = self.ssh_user(username_from_url);
+        let host = self.ssh_host.get_or_insert_with(|| {
+            SshUrl::parse(url).map(|url| ssh_config::query(&url.host)).unwrap_or_default()
+        });
+        let identities = self.ssh_identities.get_or_insert_with(|| {
+            let agent = env::var_os("SSH_AUTH_SOCK").is_some() && !host.identities_only;
+            let files = host.identity_files.iter()
+                .filter(|file| file.exists())
+                .map(|file| SshIdentity::File(file.clone()));
+            let mut identities = files.collect::<Vec<_>>();
+            if agent {
+                identities.insert(0, SshIdentity::Agent);
+            }
+            // tried from the back
+            identities.reverse();
+            identities
+        });
+
+        match identities.pop() {
+            Some(SshIdentity::Agent) => Cred::ssh_key_from_agent(&user),
+            Some(SshIdentity::File(file)) => match key_passphrase(&file) {
+                Ok(passphrase) => ssh_key(&user, &file, passphrase.as_deref()),
+                // skip keys that can't be unlocked
+                Err(()) => self.ssh_credentials(url, username_from_url),
+            },
+            None => Err(Error::from_str(format!("no more SSH identities to try for {}", url).as_str())),
+        }
+    }
+
+    fn https_credentials(&mut self, url: &str, username_from_url: Option<&str>) -> Result<Cred, Error> {
+        let host = url.parse::<Uri>().ok()
+            .and_then(|uri| uri.host().map(String::from))
+            .unwrap_or_default();
+
+        // being asked again means that the last credentials were rejected
+        if let Some((user, password, source)) = self.https_last.take() {
+            let mut cache = HTTPS_CACHE.lock().unwrap();
+            if cache.get(&host) == Some(&(user.clone(), password.clone())) {
+                cache.remove(&host);
+            }
+            drop(cache);
+            if source == HttpsSource::Helper {
+                self.helper("reject", &user, &password);
+            }
+        }
+
+        while self.https_tried < HTTPS_SOURCES.len() {
+            let mut source = HTTPS_SOURCES[self.https_tried];
+            self.https_tried += 1;
+            let found = match source {
+                HttpsSource::Cache => HTTPS_CACHE.lock().unwrap().get(&host).cloned(),
+                HttpsSource::Env => token_from_env(&host, username_from_url),
+                HttpsSource::Netrc => netrc(&host),
+                HttpsSource::Helper => {
+                    let _helper = HELPER_LOCK.lock().unwrap();
+                    // another job may have run the helper in the meantime
+                    let cached = HTTPS_CACHE.lock().unwrap().get(&host).cloned();
+                    match cached {
+                        Some(cached) => {
+                            source = HttpsSource::Cache;
+                            Some(cached)
+                        }
+                        None => self.config.as_ref().and_then(|config| {
+                            run_helper(|| {
+                                CredentialHelper::new(url)
+                                    .config(config)
+                                    .username(username_from_url)
+                                    .execute()
+                            })
+                        }),
+                    }
+                }
+            };
+
+            if let Some((user, password)) = found {
+                HTTPS_CACHE.lock().unwrap().insert(host, (user.clone(), password.clone()));
+                let cred = Cred::userpass_plaintext(&user, &password);
+                self.https_last = Some((user, password, source));
+                return cred;
+            }
+        }
+
+        Err(Error::from_str(format!("no credentials for {}", url).as_str()))
+    }
+
+    /// Lets the credential helper store the credentials it provided once
+    /// the operation they were used for succeeded.
+    pub fn approve(&self) {
+        if let Some((user, password, HttpsSource::Helper)) = &self.https_last {
+            self.helper("approve", user, password);
+        }
+    }
+
+    /// Runs `git credential approve|reject`, failures are ignored as they
+    /// only mean that the helper doesn't remember anything.
+    fn helper(&self, action: &str, user: &str, password: &str) {
+        let child = Command::new("git")
+            .arg("--git-dir").arg(&self.git_dir)
+            .args(["credential", action])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                write!(stdin, "url={}\nusername={}\npassword={}\n\n", self.url, user, password);
+            }
+            child.wait();
+        }
+    }
+}
+
+/// Name of the per-host environment variable holding an access token, e.g.
+/// `RGS_TOKEN_GITHUB_COM` for `github.com`.
+fn host_env_var(prefix: &str, host: &str) -> String {
+    let host = host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect::<String>();
+    format!("{}_{}", prefix, host)
+}
+
+/// Reads an access token for `host` from `RGS_TOKEN_<HOST>` or, for the well
+/// known forges, from the variables used by their own CLIs. The user name
+/// comes from `RGS_USER_<HOST>`, the remote URL or defaults to `oauth2`.
+fn token_from_env(host: &str, username_from_url: Option<&str>) -> Option<(String, String)> {
+    let fallback: &[&str] = match host {
+        "github.com" => &["GH_TOKEN", "GITHUB_TOKEN"],
+        "gitlab.com" => &["GITLAB_TOKEN"],
+        _ => &[],
+    };
+    let token = std::iter::once(host_env_var("RGS_TOKEN", host))
+        .chain(fallback.iter().map(|var| String::from(*var)))
+        .filter_map(|var| env::var(var).ok())
+        .find(|token| !token.is_empty())?;
+    let user = env::var(host_env_var("RGS_USER", host)).ok()
+        .or_else(|| username_from_url.map(String::from))
+        .unwrap_or_else(|| String::from("oauth2"));
+    Some((user, token))
+}
+
+/// Looks up the login and password for `host` in `$NETRC` or `~/.netrc`,
+/// falling back to the `default` entry.
+fn netrc(host: &str) -> Option<(String, String)> {
+    let path = env::var("NETRC").map(PathBuf::from).unwrap_or_else(|_| expand_tilde("~/.netrc"));
+    let content = fs::read_to_string(path).ok()?;
+    parse_netrc(&content, host)
+}
+
+fn parse_netrc(content: &str, host: &str) -> Option<(String, String)> {
+    let lines = content.lines().collect::<Vec<&str>>();
+    let mut tokens = lines.iter().enumerate()
+        .flat_map(|(n, line)| line.split_whitespace().map(move |token| (n, token)))
+        .peekable();
+    let mut found = None;
+    let mut default = None;
+    // entry currently being read, `None` when it is for another machine
+    let mut current: Option<(bool, Option<String>, Option<String>)> = None;
+
+    let mut finish = |entry: Option<(bool, Option<String>, Option<String>)>| {
+        if let Some((is_default, Some(login), Some(password))) = entry {
+            if is_default {
+                default.get_or_insert((login, password));
+            } else {
+                found.get_or_insert((login, password));
+            }
+        }
+    };
+
+    while let Some((n, token)) = tokens.next() {
+        match token {
+            "machine" => {
+                finish(current.take());
+                if tokens.next().map(|(_, name)| name) == Some(host) {
+                    current = Some((false, None, None));
+                }
+            }
+            "default" => {
+                finish(current.take());
+                current = Some((true, None, None));
+            }
+            "login" | "password" => {
+                let value = tokens.next().map(|(_, value)| String::from(value));
+                if let Some((_, login, password)) = current.as_mut() {
+                    if token == "login" { *login = value } else { *password = value }
+                }
+            }
+            "account" => {
+                tokens.next();
+            }
+            // The macro's body runs until the next empty line and may contain
+            // anything, including the keywords above.
+            "macdef" => {
+                let end = lines.iter().enumerate().skip(n + 1)
+                    .find(|(_, line)| line.trim().is_empty())
+                    .map_or(lines.len(), |(end, _)| end);
+                while tokens.next_if(|(line, _)| *line < end).is_some() {}
+            }
+            _ => {}
+        }
+    }
+    finish(current.take());
+
+    found.or(default)
+}
+
+/// Returns the passphrase `key` is loaded with, prompting for it when the
+/// key is encrypted. Fails for keys that couldn't be unlocked.
+#[cfg(unix)]
+fn key_passphrase(key: &Path) -> Result<Option<String>, ()> {
+    if !askpass::is_encrypted(key) {
+        return Ok(None);
+    }
+    askpass::passphrase(key).map(Some).ok_or(())
+}
+
+/// Prompting for passphrases needs a Unix terminal, so elsewhere keys are
+/// loaded without one and encrypted keys fail to authenticate.
+#[cfg(not(unix))]
+fn key_passphrase(_key: &Path) -> Result<Option<String>, ()> {
+    Ok(None)
+}
+
+/// Builds the credential for an SSH identity, passing the public key along
+/// when it exists next to the private one.
+fn ssh_key(user: &str, identity: &Path, passphrase: Option<&str>) -> Result<Cred, Error> {
+    let mut public = identity.as_os_str().to_owned();
+    public.push(".pub");
+    let public = PathBuf::from(public);
+    Cred::ssh_key(user, Some(public.as_path()).filter(|p| p.exists()), identity, passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(login: &str, password: &str) -> Option<(String, String)> {
+        Some((String::from(login), String::from(password)))
+    }
+
+    #[test]
+    fn netrc_machine_per_line() {
+        let netrc = "machine a.com\n  login alice\n  password one\nmachine b.com\n  login bob\n  password two\n";
+        assert_eq!(parse_netrc(netrc, "a.com"), pair("alice", "one"));
+        assert_eq!(parse_netrc(netrc, "b.com"), pair("bob", "two"));
+        assert_eq!(parse_netrc(netrc, "c.com"), None);
+    }
+
+    #[test]
+    fn netrc_multiple_tokens_per_line() {
+        let netrc = "machine a.com login alice password one machine b.com login bob account x password two";
+        assert_eq!(parse_netrc(netrc, "a.com"), pair("alice", "one"));
+        assert_eq!(parse_netrc(netrc, "b.com"), pair("bob", "two"));
+    }
+
+    #[test]
+    fn netrc_default() {
+        let netrc = "machine a.com login alice password one\ndefault login anon password guest\n";
+        assert_eq!(parse_netrc(netrc, "a.com"), pair("alice", "one"));
+        assert_eq!(parse_netrc(netrc, "c.com"), pair("anon", "guest"));
+    }
+
+    #[test]
+    fn netrc_first_entry_wins() {
+        let netrc = "machine a.com login alice password one\nmachine a.com login bob password two\n";
+        assert_eq!(parse_netrc(netrc, "a.com"), pair("alice", "one"));
+    }
+
+    #[test]
+    fn netrc_incomplete_entry() {
+        assert_eq!(parse_netrc("machine a.com login alice", "a.com"), None);
+    }
+
+    #[test]
+    fn netrc_macdef() {
+        let netrc = "machine a.com login alice macdef init\ncd /pub\nmachine b.com login mallory password evil\n\npassword one\nmachine b.com login bob password two\n";
+        assert_eq!(parse_netrc(netrc, "a.com"), pair("alice", "one"));
+        assert_eq!(parse_netrc(netrc, "b.com"), pair("bob", "two"));
+    }
+
+    #[test]
+    fn netrc_macdef_until_end() {
+        let netrc = "machine a.com login alice password one\nmacdef init\nmachine a.com login mallory password evil\n";
+        assert_eq!(parse_netrc(netrc, "a.com"), pair("alice", "one"));
+    }
+}
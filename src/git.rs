@@ -1,42 +1,254 @@
-use colored::Colorize;
+// savefile-derive puts the impls it generates inside named consts,
+// which this lint reports for every type deriving `Savefile`
+#![allow(non_local_definitions)]
+
 use git2::build::CheckoutBuilder;
 use git2::BranchType::Local;
-use git2::{Commit, Cred, CredentialType, Error, FetchOptions, Oid, ProxyOptions, RemoteCallbacks, RemoteRedirect, Repository, Revspec, Sort, StatusOptions, Time};
-use http::uri::InvalidUri;
-use http::Uri;
-use ssh_config::SSHConfig;
+use git2::{AutotagOption, Commit, Error, ErrorClass, ErrorCode, FetchOptions, FetchPrune, Oid, ProxyOptions, PushOptions, RemoteCallbacks, RemoteRedirect, Repository, RepositoryState, Revspec, Sort, Status, StatusOptions, Time};
+use serde_derive::Deserialize;
+use crate::credentials::Credentials;
+use crate::ssh_config::{self, SshUrl};
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use std::fs;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use std::path::{Path, PathBuf};
-use std::{env, fs};
+use std::str::FromStr;
 
 pub fn is_clean(path: &str) -> usize {
-    return match Repository::open(path) {
+    match Repository::open(path) {
         Ok(repo) => repo.statuses(Some(&mut StatusOptions::default())).unwrap()
             .iter().count(),
         Err(_) => 0
+    }
+}
+
+/// Per-category counts of changed files in a working tree. Renamed and
+/// deleted files are also counted as either staged or unstaged.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StatusCounts {
+    pub staged: usize,
+    pub unstaged: usize,
+    pub untracked: usize,
+    pub conflicted: usize,
+    pub renamed: usize,
+    pub deleted: usize,
+}
+
+/// How untracked files affect the status of a repository.
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default, Deserialize)]
+pub enum UntrackedMode {
+    /// Follows the repository's `status.showUntrackedFiles` setting.
+    #[default]
+    Config,
+    /// Untracked files are counted as changes.
+    Normal,
+    /// Untracked files are counted, but do not make the repository dirty.
+    Separate,
+    /// Untracked files are ignored.
+    No,
+}
+
+// @formatter:off
+impl FromStr for UntrackedMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "config"   => Ok(UntrackedMode::Config),
+            "normal"   => Ok(UntrackedMode::Normal),
+            "separate" => Ok(UntrackedMode::Separate),
+            "no"       => Ok(UntrackedMode::No),
+            _          => Err(format!("invalid untracked mode '{}' (expected config, normal, separate or no)", s)),
+        }
+    }
+}
+// @formatter:on
+
+/// Returns the number of changed files in the repository and their
+/// breakdown by category, the same way `git status` reports them.
+pub fn status(path: &str, untracked: UntrackedMode) -> (usize, StatusCounts) {
+    let mut counts = StatusCounts::default();
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(_) => return (0, counts),
+    };
+
+    // Mirror git's status.showUntrackedFiles: "no" hides untracked files
+    // and "all" lists files inside untracked directories individually.
+    let show_untracked = repo.config()
+        .and_then(|c| c.get_string("status.showUntrackedFiles"))
+        .unwrap_or_default();
+    let untracked = match untracked {
+        UntrackedMode::Config if show_untracked == "no" => UntrackedMode::No,
+        UntrackedMode::Config => UntrackedMode::Normal,
+        mode => mode,
+    };
+
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(untracked != UntrackedMode::No)
+        .recurse_untracked_dirs(show_untracked == "all")
+        .renames_head_to_index(true)
+        .renames_index_to_workdir(true);
+    let statuses = match repo.statuses(Some(&mut opts)) {
+        Ok(statuses) => statuses,
+        Err(_) => return (0, counts),
+    };
+
+    let staged = Status::INDEX_NEW | Status::INDEX_MODIFIED | Status::INDEX_DELETED
+        | Status::INDEX_RENAMED | Status::INDEX_TYPECHANGE;
+    let unstaged = Status::WT_MODIFIED | Status::WT_DELETED
+        | Status::WT_RENAMED | Status::WT_TYPECHANGE;
+
+    let mut changed = 0;
+    for entry in statuses.iter() {
+        let status = entry.status();
+        if untracked != UntrackedMode::Separate || status != Status::WT_NEW {
+            changed += 1;
+        }
+        if status.is_conflicted() {
+            counts.conflicted += 1;
+            continue;
+        }
+        if status.intersects(staged) {
+            counts.staged += 1;
+        }
+        if status.intersects(unstaged) {
+            counts.unstaged += 1;
+        }
+        if status.is_wt_new() {
+            counts.untracked += 1;
+        }
+        if status.is_index_renamed() || status.is_wt_renamed() {
+            counts.renamed += 1;
+        }
+        if status.is_index_deleted() || status.is_wt_deleted() {
+            counts.deleted += 1;
+        }
+    }
+
+    (changed, counts)
+}
+
+/// Counts stash entries of a repository.
+pub fn stash_count(path: &str) -> usize {
+    let mut repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(_) => return 0,
     };
+    let mut count = 0;
+    repo.stash_foreach(|_, _, _| {
+        count += 1;
+        true
+    }).ok();
+    count
+}
+
+/// Whether the repository at `path` has no working tree, e.g. a mirror.
+pub fn is_bare<P: AsRef<Path>>(path: P) -> bool {
+    match Repository::open(path) {
+        Ok(repo) => repo.is_bare(),
+        Err(_) => false
+    }
 }
 
 pub fn is_inside_work_tree(path: &str) -> bool {
-    let repo = Repository::open(path);
-    return if repo.is_ok() {
-        !repo.unwrap().is_bare()
-    } else {
-        false
+    match Repository::open(path) {
+        Ok(repo) => !repo.is_bare(),
+        Err(_) => false
+    }
+}
+
+/// What the `.git` entry of a directory points to.
+#[derive(Clone, Savefile)]
+pub enum GitDirKind {
+    /// A `.git` directory or a `.git` file of a submodule whose git
+    /// directory was absorbed into its superproject.
+    Repository,
+    /// A `.git` file of a linked worktree, with the working directory of its
+    /// main repository if that one isn't bare.
+    Worktree(Option<PathBuf>),
+    /// A repository without a working tree, e.g. a mirror or a backup.
+    Bare,
+}
+
+/// Recognizes a working tree by its `.git` entry and a bare repository by
+/// its layout. Only directories that look like a bare repository are opened.
+pub fn git_dir_kind(path: &Path) -> Option<GitDirKind> {
+    let dot_git = path.join(".git");
+    if dot_git.is_dir() {
+        return Some(GitDirKind::Repository);
+    }
+    if !dot_git.exists() {
+        let looks_bare = path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir();
+        return match looks_bare && Repository::open_bare(path).is_ok() {
+            true => Some(GitDirKind::Bare),
+            false => None,
+        };
+    }
+    let content = fs::read_to_string(&dot_git).ok()?;
+    let git_dir = path.join(content.strip_prefix("gitdir:")?.trim());
+    // linked worktrees live in <main>/.git/worktrees/<name>
+    let is_worktree = git_dir.parent()
+        .and_then(|parent| parent.file_name())
+        .is_some_and(|name| name == "worktrees");
+    if !is_worktree {
+        return Some(GitDirKind::Repository);
+    }
+    let common_dir = git_dir.parent().and_then(|parent| parent.parent())?;
+    let main = common_dir.file_name()
+        .filter(|name| *name == ".git")
+        .and(common_dir.parent())
+        .map(Path::to_path_buf);
+    Some(GitDirKind::Worktree(main))
+}
+
+/// Linked worktrees of a repository as their names and paths.
+pub fn worktrees<P: AsRef<Path>>(path: P) -> Vec<(String, PathBuf)> {
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(_) => return vec![],
     };
+    let names = match repo.worktrees() {
+        Ok(names) => names,
+        Err(_) => return vec![],
+    };
+    names.iter()
+        .flatten()
+        .filter_map(|name| repo.find_worktree(name).ok())
+        .filter(|worktree| worktree.validate().is_ok())
+        .filter_map(|worktree| Some((String::from(worktree.name()?), worktree.path().to_path_buf())))
+        .collect()
+}
+
+/// Checked out submodules of a repository as their paths relative to it.
+pub fn submodules<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+    let repo = match Repository::open(&path) {
+        Ok(repo) => repo,
+        Err(_) => return vec![],
+    };
+    let submodules = repo.submodules().unwrap_or_default();
+    submodules.iter()
+        .map(|submodule| submodule.path().to_path_buf())
+        .filter(|sub| path.as_ref().join(sub).join(".git").exists())
+        .collect()
 }
 
 fn current_branch(repo: &Repository) -> Result<String, Error> {
     let branch = repo.branches(Option::from(Local))?
-        .into_iter()
         .map(|b| b.unwrap().0)
         .find(|b| b.is_head());
-    if branch.is_some() {
-        let branch = branch.unwrap();
+    if let Some(branch) = branch {
         let branch = branch.name()?.unwrap();
         let branch = String::from(branch);
         return Ok(branch);
     }
-    return Err(Error::from_str("error parsing current branch"));
+    Err(Error::from_str("error parsing current branch"))
 }
 
 pub fn current_branch_from_path<P: AsRef<Path>>(path: P) -> Result<String, Error> {
@@ -44,6 +256,62 @@ pub fn current_branch_from_path<P: AsRef<Path>>(path: P) -> Result<String, Error
     current_branch(&repo)
 }
 
+/// Describes a detached HEAD by the tag pointing at it or by its short
+/// commit id.
+fn detached_head(repo: &Repository) -> Option<String> {
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    let mut tag = None;
+    repo.tag_foreach(|oid, name| {
+        let target = repo.find_object(oid, None).and_then(|o| o.peel_to_commit());
+        if target.map(|c| c.id() == commit.id()).unwrap_or(false) {
+            let name = String::from_utf8_lossy(name);
+            tag = Some(String::from(name.trim_start_matches("refs/tags/")));
+            return false;
+        }
+        true
+    }).ok();
+    tag.or_else(|| commit.as_object().short_id().ok()?.as_str().map(String::from))
+}
+
+/// Returns the name of the checked out branch, or a description of HEAD
+/// when it is detached along with `true`.
+pub fn head<P: AsRef<Path>>(path: P) -> (String, bool) {
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(_) => return (String::new(), false),
+    };
+    if repo.head_detached().unwrap_or(false) {
+        return (detached_head(&repo).unwrap_or_default(), true);
+    }
+    (current_branch(&repo).unwrap_or_default(), false)
+}
+
+/// Returns the operation in progress in a repository using the same
+/// markers as git's prompt, or an empty string if there is none.
+pub fn state<P: AsRef<Path>>(path: P) -> String {
+    let state = match Repository::open(path) {
+        Ok(repo) => repo.state(),
+        Err(_) => return String::new(),
+    };
+    // @formatter:off
+    let marker = match state {
+        RepositoryState::Clean                => "",
+        RepositoryState::Merge                => "MERGING",
+        RepositoryState::Revert
+        | RepositoryState::RevertSequence     => "REVERTING",
+        RepositoryState::CherryPick
+        | RepositoryState::CherryPickSequence => "CHERRY-PICKING",
+        RepositoryState::Bisect               => "BISECTING",
+        RepositoryState::Rebase               => "REBASE",
+        RepositoryState::RebaseInteractive    => "REBASE-i",
+        RepositoryState::RebaseMerge          => "REBASE-m",
+        RepositoryState::ApplyMailbox         => "AM",
+        RepositoryState::ApplyMailboxOrRebase => "AM/REBASE",
+    };
+    // @formatter:on
+    String::from(marker)
+}
+
 /// Lists all Local branches for a repository in a path.
 pub fn branches<P: AsRef<Path>>(path: P) -> Vec<String> {
     let repo = Repository::open(path);
@@ -52,146 +320,397 @@ pub fn branches<P: AsRef<Path>>(path: P) -> Vec<String> {
     let repo = repo.unwrap();
     let branches = repo.branches(Some(Local));
     if branches.is_err() { return result; }
-    for branch in branches.unwrap() {
-        if branch.is_ok() {
-            let name = String::from(branch.unwrap().0.name().unwrap().unwrap());
-            result.push(name);
-        }
+    for (branch, _) in branches.unwrap().flatten() {
+        let name = String::from(branch.name().unwrap().unwrap());
+        result.push(name);
     }
 
-    return result;
+    result
+}
+
+/// Options for fetching from remotes.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FetchSettings {
+    /// Removes remote tracking branches that no longer exist on the remote.
+    /// When off, `fetch.prune` and `remote.<name>.prune` are honored.
+    pub prune: bool,
+    /// Fetches all tags (`Some(true)`), no tags (`Some(false)`) or only tags
+    /// pointing at fetched commits (`None`).
+    pub tags: Option<bool>,
 }
 
-/// Performs `git fetch --all`.
-pub fn fetch_all(path: &str) -> Result<(), Error>{
+/// Number of references updated and pruned by a fetch along with the
+/// amount of data transferred.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FetchStats {
+    pub updated: usize,
+    pub pruned: usize,
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+/// Refspec of bare repositories that don't configure any. Branches that
+/// diverged from the remote are left alone instead of being overwritten.
+const BARE_REFSPEC: &str = "refs/heads/*:refs/heads/*";
+
+/// Remote tracking branches of a bare repository fetched along with
+/// `BARE_REFSPEC`, to tell how far its branches are from the remote.
+fn bare_tracking_refspec(remote: &str) -> String {
+    format!("+refs/heads/*:refs/remotes/{}/*", remote)
+}
+
+/// Progress of a running fetch as reported by the remote.
+pub enum FetchEvent<'a> {
+    /// Objects and bytes received so far.
+    Transfer { objects: usize, total_objects: usize, bytes: usize },
+    /// Textual progress sent by the remote, e.g. "Counting objects".
+    Sideband(&'a str),
+    /// Sent while waiting for a command that printed nothing since the last
+    /// event, only to check whether it should be cancelled.
+    Idle,
+}
+
+/// Performs `git fetch --all`. Transfer progress is accumulated over all
+/// remotes.
+pub fn fetch_all<F>(path: &str, settings: &FetchSettings, mut progress: F) -> Result<FetchStats, Error>
+    where F: FnMut(FetchEvent) -> bool {
     let repo = Repository::open(path)?;
+    let mut stats = FetchStats::default();
     for remote in repo.remotes()?.iter() {
-        fetch(path, &String::from(remote.unwrap()), &[])?;
+        let (objects, bytes) = (stats.objects, stats.bytes);
+        let remote_stats = fetch(path, &String::from(remote.unwrap()), &[], settings, |event| match event {
+            FetchEvent::Transfer { objects: o, total_objects, bytes: b } => progress(FetchEvent::Transfer {
+                objects: objects + o,
+                total_objects: objects + total_objects,
+                bytes: bytes + b,
+            }),
+            event => progress(event),
+        })?;
+        stats.updated += remote_stats.updated;
+        stats.pruned += remote_stats.pruned;
+        stats.objects += remote_stats.objects;
+        stats.bytes += remote_stats.bytes;
     }
-    Ok(())
+    Ok(stats)
+}
+
+/// How a remote is reached.
+enum Transport {
+    /// Through libgit2, optionally using a URL rewritten according to
+    /// ssh_config (`HostName`, `Port`, `User`).
+    Native(Option<String>),
+    /// Through the system `git` for hosts that need OpenSSH, e.g. because of
+    /// `ProxyJump` or `ProxyCommand`.
+    OpenSsh,
 }
 
-fn expand_tilde<P: AsRef<Path>>(path_user_input: P) -> PathBuf {
-    let p = path_user_input.as_ref();
-    if !p.starts_with("~") {
-        return p.to_path_buf();
+fn transport(url: &str) -> Transport {
+    let ssh_url = match SshUrl::parse(url) {
+        Some(ssh_url) => ssh_url,
+        None => return Transport::Native(None),
+    };
+    let host = ssh_config::query(&ssh_url.host);
+    if host.needs_openssh() {
+        return Transport::OpenSsh;
+    }
+    match ssh_url.resolve(&host) {
+        Some(resolved) if resolved != url => Transport::Native(Some(resolved)),
+        Some(_) => Transport::Native(None),
+        None => Transport::OpenSsh,
     }
+}
+
+/// Remote callbacks shared by all network operations along with the
+/// credentials they use, which are to be approved once the operation
+/// succeeded.
+fn remote_callbacks<'a>(repo: &Repository, url: &str) -> (RemoteCallbacks<'a>, Rc<RefCell<Credentials>>) {
+    let mut callbacks = RemoteCallbacks::default();
+    let credentials = Rc::new(RefCell::new(Credentials::new(repo, url)));
+    let resolver = Rc::clone(&credentials);
+    callbacks.credentials(move |url, username, allowed| resolver.borrow_mut().resolve(url, username, allowed));
+    (callbacks, credentials)
+}
+
+/// Wrapper for fetching from a remote. Returning `false` from `progress`
+/// cancels the fetch.
+pub fn fetch<F>(path: &str, remote: &str, branches: &[&String], settings: &FetchSettings, progress: F) -> Result<FetchStats, Error>
+    where F: FnMut(FetchEvent) -> bool {
+    let repo = Repository::open(path)?;
+    let named = repo.find_remote(remote)?;
+    let url = String::from(named.url().unwrap_or_default());
+    let mut rmt = match transport(&url) {
+        Transport::Native(None) => named,
+        Transport::Native(Some(resolved)) => repo.remote_anonymous(&resolved)?,
+        Transport::OpenSsh => return fetch_external(&repo, path, remote, branches, settings, progress),
+    };
+    // An anonymous remote only updates the references its refspecs map.
+    let refspecs = if !branches.is_empty() && rmt.name().is_some() {
+        branches.iter().map(|branch| branch.to_string()).collect::<Vec<_>>()
+    } else if !branches.is_empty() {
+        branches.iter().map(|branch| format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, remote)).collect()
+    } else if rmt.name().is_some() && !named_refspecs(&repo, remote)?.is_empty() {
+        vec![]
+    } else {
+        fetch_refspecs(&repo, remote)?
+    };
 
-    let home = env::var("HOME").unwrap();
+    let updated = Cell::new(0);
+    let pruned = Cell::new(0);
+    let progress = RefCell::new(progress);
+    let (mut callbacks, credentials) = remote_callbacks(&repo, &url);
+    callbacks.transfer_progress(|stats| (progress.borrow_mut())(FetchEvent::Transfer {
+        objects: stats.received_objects(),
+        total_objects: stats.total_objects(),
+        bytes: stats.received_bytes(),
+    }));
+    callbacks.sideband_progress(|data| {
+        let msg = String::from_utf8_lossy(data);
+        // Remotes redraw their progress using carriage returns.
+        let msg = msg.split(['\r', '\n']).rfind(|line| !line.trim().is_empty()).unwrap_or_default();
+        (progress.borrow_mut())(FetchEvent::Sideband(msg.trim()))
+    });
+    // Bare clones update their branches along with the remote tracking
+    // ones, only the latter are counted.
+    let counted = |refname: &str| !refspecs.iter().any(|refspec| refspec == BARE_REFSPEC) || !refname.starts_with("refs/heads/");
+    // Pruned references are reported as updated to a zero id.
+    callbacks.update_tips(|refname, _, new| {
+        if !counted(refname) {
+            return true;
+        }
+        if new.is_zero() {
+            pruned.set(pruned.get() + 1);
+        } else {
+            updated.set(updated.get() + 1);
+        }
+        true
+    });
 
-    if p == Path::new("~") {
-        return PathBuf::from(home);
+    let mut fetch_opts = FetchOptions::default();
+    // anonymous remotes don't read the prune settings by themselves
+    if settings.prune || prune_configured(&repo, remote) {
+        fetch_opts.prune(FetchPrune::On);
     }
+    fetch_opts.download_tags(match settings.tags {
+        Some(true) => AutotagOption::All,
+        Some(false) => AutotagOption::None,
+        None => AutotagOption::Auto,
+    });
+    let mut proxy_opts = ProxyOptions::default();
+    proxy_opts.auto();
+    fetch_opts.proxy_options(proxy_opts);
+    fetch_opts.follow_redirects(RemoteRedirect::All);
+    fetch_opts.remote_callbacks(callbacks);
 
-    return PathBuf::from(home).join(p.strip_prefix("~").unwrap());
+    rmt.fetch(&refspecs, Some(&mut fetch_opts), None)?;
+    drop(fetch_opts);
+    credentials.borrow().approve();
+
+    let transfer = rmt.stats();
+    Ok(FetchStats {
+        updated: updated.get(),
+        pruned: pruned.get(),
+        objects: transfer.received_objects(),
+        bytes: transfer.received_bytes(),
+    })
 }
 
-#[inline(always)]
-fn parse_url(url_string: &str) -> Result<Uri, InvalidUri> {
-    let mut default_schema =  String::from("ssh://");
-    default_schema.push_str(url_string);
-    default_schema.parse::<Uri>()
+/// Whether fetches from `remote` prune according to `remote.<name>.prune`,
+/// falling back to `fetch.prune`.
+fn prune_configured(repo: &Repository, remote: &str) -> bool {
+    let config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    config.get_bool(format!("remote.{}.prune", remote).as_str())
+        .or_else(|_| config.get_bool("fetch.prune"))
+        .unwrap_or(false)
 }
 
-/// Wrapper for fetching from a remote.
-pub fn fetch(path: &str, remote: &String, branches: &[&String]) -> Result<(), Error> {
-    let repo = Repository::open(path)?;
+fn named_refspecs(repo: &Repository, remote: &str) -> Result<Vec<String>, Error> {
+    let rmt = repo.find_remote(remote)?;
+    let refspecs = rmt.fetch_refspecs()?;
+    Ok(refspecs.iter().flatten().map(String::from).collect())
+}
 
-    let mut callbacks = RemoteCallbacks::default();
-    callbacks.credentials(|url_str, username_from_url, _allowed_types| {
-        // If the available type is SSH we do additional parsing
-        if _allowed_types.contains(CredentialType::SSH_KEY) {
-
-            let config_path_string = expand_tilde("~/.ssh/config");
-            let config_path = Path::new(&config_path_string);
-            // If the config file is not present we continue with the default
-            // configuration.
-            if !config_path.exists() {
-                return Cred::ssh_key_from_agent(username_from_url.unwrap());
-            }
+/// Refspecs used to fetch all branches of `remote`. Bare clones made
+/// without `--mirror` have none configured, their branches are updated
+/// directly like `git fetch origin 'refs/heads/*:refs/heads/*'` would and
+/// the remote's branches are kept as remote tracking branches.
+fn fetch_refspecs(repo: &Repository, remote: &str) -> Result<Vec<String>, Error> {
+    let refspecs = named_refspecs(repo, remote)?;
+    if refspecs.is_empty() && repo.is_bare() {
+        return Ok(vec![String::from(BARE_REFSPEC), bare_tracking_refspec(remote)]);
+    }
+    Ok(refspecs)
+}
 
-            // Parse the remote url into the Uri struct so that we can extract
-            // the host required to match SSH config file sections.
-            let url = parse_url(url_str);
-            if url.is_err() {
-                // If we fail I guess we return the default agent configuration
-                eprintln!("{}", url.err().unwrap().to_string().red());
-                return Cred::ssh_key_from_agent(username_from_url.unwrap());
+/// Remote tracking branches and tags of `remote`, used to count the
+/// references updated by an external fetch. Mirrors fetch into their
+/// branches.
+fn remote_refs(repo: &Repository, remote: &str) -> HashMap<String, Oid> {
+    let mut refs = HashMap::new();
+    let mut globs = vec![format!("refs/remotes/{}/*", remote), String::from("refs/tags/*")];
+    if repo.is_bare() && !named_refspecs(repo, remote).unwrap_or_default().is_empty() {
+        globs.push(String::from("refs/heads/*"));
+    }
+    for glob in globs {
+        if let Ok(references) = repo.references_glob(&glob) {
+            for reference in references.flatten() {
+                if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+                    refs.insert(String::from(name), oid);
+                }
             }
-            let url = url.unwrap();
+        }
+    }
+    refs
+}
+
+/// Runs `git` in `path` and returns its error output as the error when it
+/// fails. See `run_command` for `progress`.
+fn git_command<F>(path: &str, args: &[&str], progress: F) -> Result<(), Error>
+    where F: FnMut(FetchEvent) -> bool {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(path)
+        .args(args)
+        .env("GIT_TERMINAL_PROMPT", "0");
+    run_command(command, progress)
+}
 
-            // Read the config file. If in any case we fail return the default
-            // ssh-agent configuration.
-            let config_str = fs::read_to_string(config_path);
-            if config_str.is_err() {
-                return Cred::ssh_key_from_agent(username_from_url.unwrap());
+/// Runs `command`, passing each line it prints on stderr to `progress` and
+/// polling it with `FetchEvent::Idle` while the command is silent. The
+/// command is killed once `progress` returns `false`. Returns the first
+/// error line printed by a failed command as the error.
+fn run_command<F>(mut command: Command, mut progress: F) -> Result<(), Error>
+    where F: FnMut(FetchEvent) -> bool {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Error::from_str(format!("{}: {}", command.get_program().to_string_lossy(), err).as_str()))?;
+
+    // stderr is drained while waiting, a full pipe would block the command
+    let (tx, rx) = mpsc::channel();
+    let mut pipe = child.stderr.take();
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        while let Some(Ok(len @ 1..)) = pipe.as_mut().map(|pipe| pipe.read(&mut buf)) {
+            if tx.send(String::from_utf8_lossy(&buf[..len]).into_owned()).is_err() {
+                break;
             }
-            let config_str = config_str.unwrap();
+        }
+    });
 
-            let config = SSHConfig::parse_str(config_str.as_str());
-            if config.is_err() {
-                return Cred::ssh_key_from_agent(username_from_url.unwrap());
+    let mut stderr = String::new();
+    let status = loop {
+        let active = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(output) => {
+                stderr.push_str(&output);
+                // progress is redrawn using carriage returns
+                let line = output.split(['\r', '\n']).rfind(|line| !line.trim().is_empty()).unwrap_or_default();
+                progress(FetchEvent::Sideband(line.trim()))
             }
-            let config = config.unwrap();
-            let host = url.host();
-            if host.is_none() {
-                return Cred::ssh_key_from_agent(username_from_url.unwrap());
+            Err(RecvTimeoutError::Timeout) => progress(FetchEvent::Idle),
+            Err(RecvTimeoutError::Disconnected) => {
+                break child.wait().map_err(|err| Error::from_str(err.to_string().as_str()))?;
             }
-            let host_config = config.query(url.host().unwrap());
-
-            // We care only about two ConfigKeys - User and IdentityFile.
-
-            // First we parse the identity file from the configuration or
-            // fallback to a sane default.
-            let identity_file = host_config.get("IdentityFile");
-            let priv_key_path =  match identity_file {
-                None => {
-                    let paths = vec![
-                        "~/.ssh/id_rsa",
-                        "~/.ssh/id_ed25519",
-                        "~/.ssh/id_ecdsa",
-                        "~/.ssh/id_dsa",
-                    ];
-                    paths.iter()
-                        .map(|p| PathBuf::from(expand_tilde(p)))
-                        .find(|p| p.exists())
-                        .unwrap_or(PathBuf::from("~/.ssh/id_rsa"))
-                },
-                Some(iden) => expand_tilde(PathBuf::from(iden))
-            };
-
-            // Second is the User. If it is not overridden we default to the
-            // one provided by the remote URL.
-            let user = host_config.get("User");
-            let user =  match user {
-                None => username_from_url.unwrap_or("git"),
-                Some(_user) => _user
-            };
-
-            let priv_key = Path::new(&priv_key_path);
-            Cred::ssh_key(
-                user,
-                None,
-                priv_key,
-                None,
-            )
-        } else {
-            Cred::default()
+        };
+        if !active {
+            child.kill();
+            child.wait();
+            return Err(Error::new(ErrorCode::User, ErrorClass::Net, "operation cancelled"));
         }
-    });
+    };
 
-    // Only thing needed apart from the defaults is the credentials callback.
-    let mut fetch_opts = FetchOptions::default();
+    if status.success() {
+        return Ok(());
+    }
+    let lines = || stderr.split(['\r', '\n']).map(str::trim).filter(|line| !line.is_empty());
+    let msg = lines().find(|line| line.starts_with("fatal:") || line.starts_with("error:"))
+        .or_else(|| lines().next_back())
+        .unwrap_or("command failed");
+    Err(Error::from_str(msg))
+}
+
+/// Fetches through the system `git` so that OpenSSH handles the connection.
+fn fetch_external<F>(repo: &Repository, path: &str, remote: &str, branches: &[&String], settings: &FetchSettings, progress: F) -> Result<FetchStats, Error>
+    where F: FnMut(FetchEvent) -> bool {
+    // progress is shown and counts as activity for the fetch timeout
+    let mut args = vec!["fetch", "--progress"];
+    if settings.prune {
+        args.push("--prune");
+    }
+    match settings.tags {
+        Some(true) => args.push("--tags"),
+        Some(false) => args.push("--no-tags"),
+        None => {}
+    }
+    args.push(remote);
+    args.extend(branches.iter().map(|branch| branch.as_str()));
+    let tracking_refspec = bare_tracking_refspec(remote);
+    if branches.is_empty() && named_refspecs(repo, remote)?.is_empty() && repo.is_bare() {
+        args.push(BARE_REFSPEC);
+        args.push(&tracking_refspec);
+    }
+
+    let before = remote_refs(repo, remote);
+    git_command(path, &args, progress)?;
+    let after = remote_refs(repo, remote);
+
+    Ok(FetchStats {
+        updated: after.iter().filter(|(name, oid)| before.get(*name) != Some(oid)).count(),
+        pruned: before.keys().filter(|name| !after.contains_key(*name)).count(),
+        ..Default::default()
+    })
+}
+
+/// Pushes a local branch to its configured upstream branch.
+pub fn push(path: &str, branch: &str) -> Result<(), Error> {
+    let repo = Repository::open(path)?;
+    let local_refname = format!("refs/heads/{}", branch);
+    let remote = repo.branch_upstream_remote(&local_refname)
+        .map_err(|_| Error::from_str("no upstream"))?;
+    let remote = remote.as_str().unwrap_or_default();
+    let merge = repo.config()?.get_string(format!("branch.{}.merge", branch).as_str())?;
+    let refspec = format!("{}:{}", local_refname, merge);
+
+    let named = repo.find_remote(remote)?;
+    let url = String::from(named.pushurl().or(named.url()).unwrap_or_default());
+    let mut rmt = match transport(&url) {
+        Transport::Native(None) => named,
+        Transport::Native(Some(resolved)) => repo.remote_anonymous(&resolved)?,
+        Transport::OpenSsh => return git_command(path, &["push", "--quiet", remote, &refspec], |_| true),
+    };
+
+    let (mut callbacks, credentials) = remote_callbacks(&repo, &url);
+    // Rejected references don't fail the push itself, they are only reported
+    // through this callback.
+    callbacks.push_update_reference(|refname, status| match status {
+        Some(msg) => Err(Error::from_str(format!("{} rejected: {}", refname, msg).as_str())),
+        None => Ok(()),
+    });
+    // Pushing through an anonymous remote doesn't move the remote-tracking
+    // branch, so it is updated here like `git push` does. Its name follows
+    // from the remote's fetch refspec, there is none if it doesn't map the
+    // upstream branch.
+    let tracking_ref = repo.branch_upstream_name(&local_refname).ok()
+        .and_then(|name| name.as_str().map(String::from));
+
+    let mut push_opts = PushOptions::default();
     let mut proxy_opts = ProxyOptions::default();
     proxy_opts.auto();
-    fetch_opts.proxy_options(proxy_opts);
-    fetch_opts.follow_redirects(RemoteRedirect::All);
-    fetch_opts.remote_callbacks(callbacks);
+    push_opts.proxy_options(proxy_opts);
+    push_opts.remote_callbacks(callbacks);
 
-    let mut rmt = repo.find_remote(remote)?;
-    let msg = format!("fetching {}:{}", path, remote);
-    eprintln!("{}", msg.green());
-    rmt.fetch(branches, Some(&mut fetch_opts), None)
+    rmt.push(&[refspec.as_str()], Some(&mut push_opts))?;
+    drop(push_opts);
+    credentials.borrow().approve();
+
+    if let Some(tracking_ref) = tracking_ref {
+        let oid = repo.refname_to_id(&local_refname)?;
+        repo.reference(&tracking_ref, oid, true, "push")?;
+    }
+    Ok(())
 }
 
 #[inline(always)]
@@ -199,9 +718,34 @@ fn rev_from_to(rev: &Revspec) -> (Oid, Oid) {
     (rev.from().unwrap().id(), rev.to().unwrap().id())
 }
 
-pub fn ahead_behind(path: &str, branch: &String) -> Result<(usize, usize), Error> {
+/// Returns the upstream of a local branch as configured by
+/// `branch.<name>.remote` and `branch.<name>.merge`, e.g. `origin/master`.
+/// `None` means that the branch doesn't track anything.
+pub fn upstream<P: AsRef<Path>>(path: P, branch: &str) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    upstream_of(&repo, branch)
+}
+
+/// Returns the name of the remote a local branch tracks.
+pub fn upstream_remote<P: AsRef<Path>>(path: P, branch: &str) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    let remote = repo.branch_upstream_remote(format!("refs/heads/{}", branch).as_str()).ok()?;
+    remote.as_str().map(String::from)
+}
+
+fn upstream_of(repo: &Repository, branch: &str) -> Option<String> {
+    let name = repo.branch_upstream_name(format!("refs/heads/{}", branch).as_str()).ok()?;
+    let name = name.as_str()?;
+    let name = name.strip_prefix("refs/remotes/")
+        .or_else(|| name.strip_prefix("refs/heads/"))
+        .unwrap_or(name);
+    Some(String::from(name))
+}
+
+/// Counts commits of HEAD that are ahead and behind of the given upstream.
+pub fn ahead_behind(path: &str, upstream: &str) -> Result<(usize, usize), Error> {
     let repo = Repository::open(path)?;
-    let rev = repo.revparse(format!("HEAD..origin/{}", branch).as_str())?;
+    let rev = repo.revparse(format!("HEAD..{}", upstream).as_str())?;
     let (from, to) = rev_from_to(&rev);
     let res = repo.graph_ahead_behind(from, to)?;
 
@@ -240,37 +784,147 @@ pub fn ahead_behind_remote(path: &str) -> Result<Vec<(String, String, usize, usi
     Ok(result)
 }
 
-pub fn fast_forward<P: AsRef<Path>>(path: &P, reference: &String) -> Result<(), Error> {
+/// Lists local branches that neither track an upstream nor have a branch of
+/// the same name on any remote, along with the number of their commits that
+/// are not on any remote.
+pub fn local_only_branches(path: &str) -> Result<Vec<(String, usize)>, Error> {
+    let repo = Repository::open(path)?;
+    let mut result = vec![];
+    let remotes = repo.remotes()?;
+    let remotes = remotes
+        .iter()
+        .flatten()
+        .collect::<Vec<&str>>();
+    for branch in repo.branches(Some(Local))? {
+        let (branch, _) = branch?;
+        if branch.upstream().is_ok() {
+            continue;
+        }
+        let name = String::from(branch.name()?.unwrap());
+        let on_remote = remotes.iter()
+            .any(|remote| repo.find_reference(format!("refs/remotes/{}/{}", remote, name).as_str()).is_ok());
+        if on_remote {
+            continue;
+        }
+
+        let tip = match branch.get().target() {
+            Some(tip) => tip,
+            None => continue,
+        };
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(tip)?;
+        revwalk.hide_glob("refs/remotes/*")?;
+        result.push((name, revwalk.count()));
+    }
+
+    Ok(result)
+}
+
+/// Returns the default branch of the repository at `path`.
+pub fn default_branch<P: AsRef<Path>>(path: P) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    default_branch_of(&repo)
+}
+
+/// Resolves the default branch of a repository from the `HEAD` of its
+/// remotes, preferring origin, and falls back to a local main or master.
+fn default_branch_of(repo: &Repository) -> Option<String> {
+    let remotes = repo.remotes().ok()?;
+    let mut remotes = remotes.iter().flatten().collect::<Vec<&str>>();
+    remotes.sort_by_key(|remote| *remote != "origin");
+    for remote in remotes {
+        let head = repo.find_reference(format!("refs/remotes/{}/HEAD", remote).as_str());
+        if let Some(target) = head.ok().as_ref().and_then(|h| h.symbolic_target()) {
+            let prefix = format!("refs/remotes/{}/", remote);
+            return target.strip_prefix(prefix.as_str()).map(String::from);
+        }
+    }
+
+    ["main", "master"].iter()
+        .find(|name| repo.find_branch(name, Local).is_ok())
+        .map(|name| String::from(*name))
+}
+
+/// Local branch that is either fully merged into the default branch or
+/// whose upstream has been deleted from the remote.
+pub struct StaleBranch {
+    pub name: String,
+    pub merged: bool,
+    pub gone: bool,
+    pub time: Time,
+}
+
+/// Lists local branches that are fully merged into the default branch or
+/// whose remote tracking branch is gone. The checked out branch and the
+/// default branch itself are never reported.
+pub fn stale_branches(path: &str) -> Result<Vec<StaleBranch>, Error> {
+    let repo = Repository::open(path)?;
+    let config = repo.config()?;
+    let default = default_branch_of(&repo);
+    let default_tip = default.as_ref()
+        .and_then(|name| repo.find_branch(name, Local).ok())
+        .and_then(|branch| branch.get().target());
+
+    let mut result = vec![];
+    for branch in repo.branches(Some(Local))? {
+        let (branch, _) = branch?;
+        let name = String::from(branch.name()?.unwrap());
+        if branch.is_head() || Some(&name) == default.as_ref() {
+            continue;
+        }
+        let tip = match branch.get().target() {
+            Some(tip) => tip,
+            None => continue,
+        };
+
+        let merged = match default_tip {
+            Some(default_tip) => tip == default_tip || repo.graph_descendant_of(default_tip, tip)?,
+            None => false,
+        };
+        // Tracking is configured, but the remote branch doesn't exist anymore.
+        let gone = config.get_string(format!("branch.{}.merge", name).as_str()).is_ok()
+            && branch.upstream().is_err();
+
+        if merged || gone {
+            let time = repo.find_commit(tip)?.time();
+            result.push(StaleBranch { name, merged, gone, time });
+        }
+    }
+
+    Ok(result)
+}
+
+/// Deletes a local branch.
+pub fn delete_branch(path: &str, name: &str) -> Result<(), Error> {
+    let repo = Repository::open(path)?;
+    let mut branch = repo.find_branch(name, Local)?;
+    branch.delete()
+}
+
+/// Fast-forwards a local branch to its upstream. Returns whether the branch
+/// has been moved.
+pub fn fast_forward<P: AsRef<Path>>(path: &P, branch: &str) -> Result<bool, Error> {
     if is_clean(path.as_ref().to_str().unwrap()) > 0 {
         return Err(Error::from_str("Repository is not clean"));
     }
 
     let repo = Repository::open(path)?;
-    let adjusted_remote_reference = if reference.contains("/") {
-        reference.clone()
-    } else {
-        format!("origin/{}", reference)
-    };
-    let adjusted_local_reference = if reference.contains("/") {
-        reference.split("/").last().unwrap().to_string()
-    } else {
-        reference.clone()
-    };
-
-    let remote_refname = format!("refs/remotes/{}", adjusted_remote_reference);
-    let local_refname = format!("refs/heads/{}", adjusted_local_reference);
+    let local_refname = format!("refs/heads/{}", branch);
+    let remote_refname = repo.branch_upstream_name(&local_refname)
+        .map_err(|_| Error::from_str("no upstream"))?;
+    let remote_refname = remote_refname.as_str().unwrap_or_default();
 
-    let fetch_head = repo.find_reference(&remote_refname)?;
+    let fetch_head = repo.find_reference(remote_refname)?;
     let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
     let mut local_head = repo.find_reference(&local_refname)?;
 
     let analysis = repo.merge_analysis_for_ref(&local_head, &[&fetch_commit])?;
     if analysis.0.is_up_to_date() {
-        Ok(())
+        Ok(false)
     } else if analysis.0.is_fast_forward() {
         local_head.set_target(fetch_commit.id(), "fast-forward")?;
         repo.checkout_head(Some(CheckoutBuilder::default().force()))?;
-        Ok(())
+        Ok(true)
     } else if analysis.0.is_normal() {
         Err(Error::from_str("Merge required"))
     } else {
@@ -291,14 +945,15 @@ impl From<&Commit<'_>> for CommitInfo {
             summary: commit.summary().unwrap_or_default().to_string().clone(),
             author: commit.author().name().unwrap_or_default().to_string().clone(),
             id: commit.id().to_string().clone(),
-            time: commit.time().clone(),
+            time: commit.time(),
         }
     }
 }
 
-pub fn behind_commits(path: &str, branch: &String) -> Result<Vec<CommitInfo>, Error> {
+pub fn behind_commits(path: &str, branch: &str) -> Result<Vec<CommitInfo>, Error> {
     let repo = Repository::open(path)?;
-    let rev = repo.revparse(format!("HEAD..origin/{}", branch).as_str())?;
+    let upstream = upstream_of(&repo, branch).ok_or_else(|| Error::from_str("no upstream"))?;
+    let rev = repo.revparse(format!("HEAD..{}", upstream).as_str())?;
     let (from, to) = rev_from_to(&rev);
     let mut revwalk = repo.revwalk()?;
     revwalk.set_sorting(Sort::TIME & Sort::TOPOLOGICAL);
@@ -306,9 +961,50 @@ pub fn behind_commits(path: &str, branch: &String) -> Result<Vec<CommitInfo>, Er
     revwalk.hide(from);
     let mut commits = vec![];
 
-    for entry in revwalk.into_iter() {
+    for entry in revwalk {
         let commit = repo.find_commit(entry?)?;
         commits.push(CommitInfo::from(&commit));
     }
     Ok(commits)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn silent_command_is_killed() {
+        let start = Instant::now();
+        let result = run_command(sh("sleep 10"), |event| {
+            assert!(matches!(event, FetchEvent::Idle));
+            start.elapsed() < Duration::from_millis(300)
+        });
+        assert_eq!(result.unwrap_err().message(), "operation cancelled");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn output_is_passed_on() {
+        let mut lines = Vec::new();
+        let result = run_command(sh("printf 'one\\rtwo\\n' >&2; sleep 0.3; echo three >&2"), |event| {
+            if let FetchEvent::Sideband(line) = event {
+                lines.push(String::from(line));
+            }
+            true
+        });
+        assert!(result.is_ok());
+        assert_eq!(lines, ["two", "three"]);
+    }
+
+    #[test]
+    fn error_line_is_returned() {
+        let result = run_command(sh("echo 'remote: Counting objects' >&2; echo 'fatal: unreachable' >&2; exit 128"), |_| true);
+        assert_eq!(result.unwrap_err().message(), "fatal: unreachable");
+    }
+}
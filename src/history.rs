@@ -0,0 +1,159 @@
+// savefile-derive puts the impls it generates inside named consts,
+// which this lint reports for every type deriving `Savefile`
+#![allow(non_local_definitions)]
+
+use savefile::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache::{self, ReadError};
+use crate::lang::Project;
+
+const SNAPSHOT_FILE: &str = "status";
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// Status of every repository as of the end of a run, used by `--diff` to
+/// tell what changed since.
+#[derive(Savefile, Default)]
+pub struct Snapshot {
+    /// Seconds since the epoch.
+    pub time: u64,
+    pub repos: HashMap<String, RepoStatus>,
+}
+
+#[derive(Savefile, Clone, PartialEq)]
+pub struct RepoStatus {
+    pub branch: String,
+    pub detached: bool,
+    pub state: String,
+    pub modified: usize,
+    pub stashes: usize,
+    pub ahead: usize,
+    pub behind: usize,
+}
+
+impl From<&Project> for RepoStatus {
+    fn from(p: &Project) -> Self {
+        RepoStatus {
+            branch: p.current_branch.clone(),
+            detached: p.detached,
+            state: p.state.clone(),
+            modified: p.modified,
+            stashes: p.stashes,
+            ahead: p.ahead_behind.0,
+            behind: p.ahead_behind.1,
+        }
+    }
+}
+
+fn snapshot_path() -> PathBuf {
+    cache::cache_dir().join(SNAPSHOT_FILE)
+}
+
+impl Snapshot {
+    /// Reads the snapshot of the previous run, `None` if there is none
+    /// that can be used.
+    pub fn load() -> Option<Snapshot> {
+        match cache::read(&snapshot_path(), SNAPSHOT_VERSION) {
+            Ok(snapshot) => Some(snapshot),
+            Err(ReadError::Corrupt(err)) => {
+                eprintln!("cgs: discarding corrupt status {}: {}", snapshot_path().display(), err);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Records the status of `projs`. Repositories that were not part of
+    /// this run, e.g. from another root, are kept unless they are gone.
+    pub fn update<'a>(&mut self, projs: impl Iterator<Item=&'a Project>) {
+        self.time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        self.repos.retain(|path, _| Path::new(path).exists());
+        for p in projs {
+            self.repos.insert(p.path.clone(), RepoStatus::from(p));
+        }
+    }
+
+    pub fn save(&self) -> Result<(), SavefileError> {
+        cache::write(&snapshot_path(), SNAPSHOT_VERSION, self)
+    }
+}
+
+/// Describes how a repository changed since `prev`, e.g. `dirty` or
+/// `main→feature`. Empty if nothing changed.
+pub fn changes(prev: Option<&RepoStatus>, now: &RepoStatus) -> Vec<String> {
+    let prev = match prev {
+        Some(prev) => prev,
+        None => return vec![String::from("new")],
+    };
+    let mut changes = vec![];
+    if prev.branch != now.branch || prev.detached != now.detached {
+        changes.push(format!("{}→{}", head(prev), head(now)));
+    }
+    if prev.state != now.state {
+        changes.push(if now.state.is_empty() { format!("{} done", prev.state) } else { now.state.clone() });
+    }
+    if (prev.modified > 0) != (now.modified > 0) {
+        changes.push(String::from(if now.modified > 0 { "dirty" } else { "clean" }));
+    }
+    if prev.stashes != now.stashes {
+        changes.push(format!("${}→{}", prev.stashes, now.stashes));
+    }
+    if prev.ahead != now.ahead {
+        changes.push(format!("↑{}→{}", prev.ahead, now.ahead));
+    }
+    if prev.behind != now.behind {
+        changes.push(format!("↓{}→{}", prev.behind, now.behind));
+    }
+    changes
+}
+
+fn head(status: &RepoStatus) -> String {
+    match status.detached {
+        true => format!("({})", status.branch),
+        false => status.branch.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn status(branch: &str) -> RepoStatus {
+        RepoStatus { branch: String::from(branch), detached: false, state: String::new(), modified: 0, stashes: 0, ahead: 0, behind: 0 }
+    }
+
+    #[test]
+    fn added_repository() {
+        assert_eq!(changes(None, &status("main")), ["new"]);
+    }
+
+    #[test]
+    fn unchanged_repository() {
+        assert!(changes(Some(&status("main")), &status("main")).is_empty());
+    }
+
+    #[test]
+    fn changed_repository() {
+        let prev = RepoStatus { modified: 3, stashes: 1, state: String::from("rebase"), ..status("main") };
+        let now = RepoStatus { detached: true, ahead: 2, behind: 1, ..status("1a2b3c4") };
+        assert_eq!(changes(Some(&prev), &now), ["main→(1a2b3c4)", "rebase done", "clean", "$1→0", "↑0→2", "↓0→1"]);
+        let now = RepoStatus { modified: 1, state: String::from("merge"), ..prev.clone() };
+        assert_eq!(changes(Some(&prev), &now), ["merge"]);
+        assert_eq!(changes(Some(&status("main")), &RepoStatus { modified: 1, ..status("main") }), ["dirty"]);
+    }
+
+    #[test]
+    fn removed_repository() {
+        let mut snapshot = Snapshot::default();
+        let gone = env::temp_dir().join(format!("rgs-history-{}-gone", std::process::id()));
+        snapshot.repos.insert(gone.to_string_lossy().into_owned(), status("main"));
+        let kept = env::temp_dir().to_string_lossy().into_owned();
+        snapshot.repos.insert(kept.clone(), status("main"));
+        snapshot.update(std::iter::empty());
+        assert_eq!(snapshot.repos.keys().collect::<Vec<_>>(), [&kept]);
+        assert!(snapshot.time > 0);
+    }
+}
@@ -1,4 +1,9 @@
+// savefile-derive puts the impls it generates inside named consts,
+// which this lint reports for every type deriving `Savefile`
+#![allow(non_local_definitions)]
+
 use crate::git;
+use crate::git::{FetchStats, StatusCounts};
 use std::collections::HashMap;
 
 #[derive(Clone, Savefile)]
@@ -8,16 +13,56 @@ pub struct Project {
     pub path: String,
     pub current_branch: String,
     pub branches: Vec<String>,
+    /// Whether the repository has no working tree. Working tree checks like
+    /// the status are skipped for it.
+    #[savefile_ignore]
+    pub bare: bool,
+    #[savefile_ignore]
+    pub default_branch: Option<String>,
+    #[savefile_ignore]
+    pub detached: bool,
+    #[savefile_ignore]
+    pub state: String,
+    #[savefile_ignore]
+    pub upstream: Option<String>,
+    /// Remote `upstream` belongs to. Remote names may contain slashes so
+    /// it can't be told from `upstream` alone.
+    #[savefile_ignore]
+    pub upstream_remote: Option<String>,
     #[savefile_ignore]
     pub modified: usize,
     #[savefile_ignore]
+    #[savefile_introspect_ignore]
+    pub status: StatusCounts,
+    #[savefile_ignore]
+    pub stashes: usize,
+    /// Whether stashes make this repository not clean.
+    #[savefile_ignore]
+    pub stash_dirty: bool,
+    #[savefile_ignore]
     pub time: u64,
     #[savefile_ignore]
+    #[savefile_introspect_ignore]
+    pub fetch_stats: FetchStats,
+    /// Whether the last fetch was cancelled because the remote did not
+    /// respond in time.
+    #[savefile_ignore]
+    pub timed_out: bool,
+    #[savefile_ignore]
     pub ahead_behind: (usize, usize),
     #[savefile_ignore]
     pub remote_ahead_behind: HashMap<String, (usize, usize)>,
+    /// Local branches without an upstream or a remote counterpart and the
+    /// number of their commits that are not on any remote.
+    #[savefile_ignore]
+    pub local_branches: HashMap<String, usize>,
     #[savefile_ignore]
     pub fast_forwarded: bool,
+    #[savefile_ignore]
+    pub pushed: bool,
+    /// How the status changed since the previous run, see `--diff`.
+    #[savefile_ignore]
+    pub changes: Vec<String>,
 }
 
 #[derive(Clone, Savefile)]
@@ -31,31 +76,110 @@ pub struct Group {
 
 impl Project {
     pub fn new(name: &str, path: &str, grp_name: &str) -> Self {
+        let (current_branch, detached) = git::head(path);
         Project {
             name: String::from(name),
             path: String::from(path),
             grp_name: String::from(grp_name),
-            current_branch: git::current_branch_from_path(path).unwrap_or_default(),
+            current_branch,
             branches: git::branches(path),
+            bare: git::is_bare(path),
+            default_branch: None,
+            detached,
+            state: String::new(),
+            upstream: None,
+            upstream_remote: None,
             modified: 0,
+            status: StatusCounts::default(),
+            stashes: 0,
+            stash_dirty: false,
             ahead_behind: (0, 0),
             remote_ahead_behind: HashMap::new(),
+            local_branches: HashMap::new(),
             time: 0,
+            fetch_stats: FetchStats::default(),
+            timed_out: false,
             fast_forwarded: false,
+            pushed: false,
+            changes: vec![],
         }
     }
 
     #[inline]
     pub fn is_clean(&self) -> bool {
-        self.modified == 0 && !self.is_ahead_behind()
+        self.modified == 0 && !self.is_ahead_behind() && self.state.is_empty()
+            && !(self.stash_dirty && self.stashes > 0) && !self.has_unpushed_branches()
+    }
+
+    /// Whether any local-only branch has commits that were never pushed.
+    #[inline]
+    pub fn has_unpushed_branches(&self) -> bool {
+        self.local_branches.values().any(|count| *count > 0)
+    }
+
+    /// Whether the default branch is checked out. Repositories with an
+    /// unknown default branch and bare ones are treated as being on it.
+    #[inline]
+    pub fn is_on_default_branch(&self) -> bool {
+        if self.bare {
+            return true;
+        }
+        match &self.default_branch {
+            Some(default) => !self.detached && *default == self.current_branch,
+            None => true,
+        }
+    }
+
+    /// Checked out branch as shown in the listing. Detached HEAD is shown in
+    /// parentheses, the default branch is appended when a different one is
+    /// checked out and so is an operation in progress, e.g.
+    /// `feature≠main|REBASE`.
+    pub fn head_label(&self) -> String {
+        let mut label = if self.detached {
+            format!("({})", self.current_branch)
+        } else {
+            self.current_branch.clone()
+        };
+        if !self.is_on_default_branch() {
+            label = format!("{}≠{}", label, self.default_branch.as_ref().unwrap());
+        }
+        if !self.state.is_empty() {
+            label = format!("{}|{}", label, self.state);
+        }
+        label
+    }
+
+    /// Remote of the current branch's upstream, defaults to origin for
+    /// branches that don't track anything.
+    #[inline]
+    pub fn upstream_remote(&self) -> &str {
+        self.upstream_remote.as_deref().unwrap_or("origin")
+    }
+
+    #[inline]
+    fn is_upstream_remote(&self, key: &str) -> bool {
+        key.starts_with(format!("{}/", self.upstream_remote()).as_str())
     }
 
     #[inline]
     pub fn is_ahead_behind(&self) -> bool {
         self.ahead_behind.0 > 0 || self.ahead_behind.1 > 0
-            // If the branch is on origin and there are differences. I an generally most
+            // If the branch is on the upstream remote and there are differences. I an generally most
             // interested in changes on the current remote while working.
-            || self.remote_ahead_behind.iter().any(|x| x.0.starts_with("origin") && (x.1.0 > 0 || x.1.1 > 0))
+            || self.remote_ahead_behind.iter().any(|x| self.is_upstream_remote(x.0) && (x.1.0 > 0 || x.1.1 > 0))
+    }
+
+    #[inline]
+    pub fn is_ahead(&self) -> bool {
+        self.ahead_behind.0 > 0
+            || self.has_unpushed_branches()
+            || self.remote_ahead_behind.iter().any(|x| self.is_upstream_remote(x.0) && x.1.0 > 0)
+    }
+
+    #[inline]
+    pub fn is_behind(&self) -> bool {
+        self.ahead_behind.1 > 0
+            || self.remote_ahead_behind.iter().any(|x| self.is_upstream_remote(x.0) && x.1.1 > 0)
     }
 }
 
@@ -1,11 +1,20 @@
 #![allow(unused_must_use)]
 
+#[cfg(unix)]
+mod askpass;
+mod cache;
+mod codeignore;
+mod config;
+mod credentials;
 mod git;
+mod history;
 mod lang;
 mod rgs;
 mod print;
 mod rgs_opt;
 mod notify;
+mod ssh_config;
+mod progress;
 
 use colored::*;
 use crate::rgs::{Rgs};
@@ -19,10 +28,14 @@ use std::process;
 extern crate savefile_derive;
 
 fn main() {
-    let mut opt: RgsOptStruct = RgsOptStruct::from_args();
+    let matches = RgsOptStruct::clap().get_matches();
+    let mut opt = RgsOptStruct::from_clap(&matches);
     let now = Instant::now();
 
-    opt.load_profile();
+    if let Err(err) = opt.load_config(&matches) {
+        eprintln!("cgs: {}", err);
+        process::exit(2);
+    }
     let rgs_opt = RgsOpt::from(&opt);
     let mut rgs = Rgs::new(rgs_opt);
 
@@ -38,6 +51,10 @@ fn main() {
     if opt.time {
         eprintln!("{}", format!("{}ms", time.as_millis()).black());
     }
+
+    if opt.check {
+        process::exit(rgs.check_status());
+    }
 }
 
 
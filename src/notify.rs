@@ -3,10 +3,10 @@ use crate::git;
 use std::process;
 use std::path::PathBuf;
 
-pub fn notify(repo: &PathBuf, notify_body: &String) {
+pub fn notify(repo: &PathBuf, notify_body: &str) {
     match Notification::new()
         .summary(format!("cgs watch ({})", repo.file_name().unwrap().to_str().unwrap()).as_str())
-        .body(notify_body.as_str())
+        .body(notify_body)
         .icon("git")
         .action("pull", "Pull")
         .action("open", "Open")
@@ -39,6 +39,9 @@ pub fn notify(repo: &PathBuf, notify_body: &String) {
                             let command = "explorer";
                         #[cfg(target_os = "macos")]
                             let command = "open";
+                        // the opener is left running on its own, waiting for
+                        // it would block until the opened window is closed
+                        #[allow(clippy::zombie_processes)]
                         process::Command::new(command)
                             .arg(repo.to_str().unwrap())
                             .spawn()
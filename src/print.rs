@@ -2,9 +2,12 @@ use colored::*;
 use std::cmp::Ordering;
 use std::str::FromStr;
 
+use crate::git::{StaleBranch, StatusCounts};
 use crate::lang::{Project, Group};
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use chrono::DateTime;
 
 // @formatter:off
 const COLOR_DIRTY:  &str = "yellow";
@@ -13,11 +16,27 @@ const COLOR_FG:     &str = "blue";
 const COLOR_BRANCH: &str = "blue";
 const COLOR_AHEAD:  &str = "cyan";
 const COLOR_BEHIND: &str = "magenta";
+const COLOR_STAGED: &str = "green";
+const COLOR_UNTRACKED: &str = "white";
+const COLOR_CONFLICTED: &str = "red";
+const COLOR_STASH: &str = "cyan";
 
 const SYMBOL_MOD:   &str = "±";
+const SYMBOL_STASH: &str = "$";
 const SYMBOL_AHEAD: &str = "↑";
 const SYMBOL_BEHIND:&str = "↓";
 const SYMBOL_FF:    &str = "→";
+const SYMBOL_PUSH:  &str = "←";
+const SYMBOL_NO_UPSTREAM: &str = "∅";
+const SYMBOL_TIMED_OUT: &str = "⧖";
+const LABEL_BARE:   &str = "bare";
+
+const SYMBOL_STAGED:     &str = "+";
+const SYMBOL_UNSTAGED:   &str = "!";
+const SYMBOL_UNTRACKED:  &str = "?";
+const SYMBOL_CONFLICTED: &str = "=";
+const SYMBOL_RENAMED:    &str = "»";
+const SYMBOL_DELETED:    &str = "✘";
 // @formatter:on
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
@@ -50,8 +69,9 @@ impl FromStr for OutputType {
 }
 // @formatter:on
 
-#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
 pub enum SummaryType {
+    #[default]
     Default,
     Verbose,
     VeryVerbose,
@@ -67,19 +87,15 @@ impl SummaryType {
     }
 }
 
-impl Default for SummaryType {
-    fn default() -> Self {
-        SummaryType::Default
-    }
-}
-
-#[derive(Eq, PartialEq, Debug, Clone, Copy, Deserialize)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Deserialize, Default)]
 pub enum SortType {
+    #[default]
     None,
     Dir,
     Time,
     Mod,
     AheadBehind,
+    Status(StatusCategory),
 }
 
 // @formatter:off
@@ -92,7 +108,10 @@ impl FromStr for SortType {
             "time"                  | "t" => SortType::Time,
             "ahead-behind"  | "ab"  | "a" => SortType::AheadBehind,
             "directory"     | "dir" | "d" => SortType::Dir,
-            _                             => SortType::None,
+            _ => match StatusCategory::from_str(s) {
+                Ok(category)              => SortType::Status(category),
+                Err(_)                    => SortType::None,
+            },
         };
         Ok(sort)
     }
@@ -112,23 +131,267 @@ impl From<&String> for SortType {
     }
 }
 
-impl Default for SortType {
-    fn default() -> Self {
-        SortType::None
+// @formatter:off
+impl Display for SortType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SortType::None        => "none",
+            SortType::Dir         => "directory",
+            SortType::Time        => "time",
+            SortType::Mod         => "modifications",
+            SortType::AheadBehind => "ahead-behind",
+            SortType::Status(c)   => return write!(f, "{}", c),
+        };
+        write!(f, "{}", name)
+    }
+}
+// @formatter:on
+
+/// Categories of changed files used for sorting and filtering.
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash, Deserialize)]
+pub enum StatusCategory {
+    Staged,
+    Unstaged,
+    Untracked,
+    Conflicted,
+    Renamed,
+    Deleted,
+}
+
+// @formatter:off
+impl StatusCategory {
+    pub fn count(&self, status: &StatusCounts) -> usize {
+        match self {
+            StatusCategory::Staged     => status.staged,
+            StatusCategory::Unstaged   => status.unstaged,
+            StatusCategory::Untracked  => status.untracked,
+            StatusCategory::Conflicted => status.conflicted,
+            StatusCategory::Renamed    => status.renamed,
+            StatusCategory::Deleted    => status.deleted,
+        }
     }
 }
 
-impl Display for SortType {
+impl FromStr for StatusCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "staged"     => Ok(StatusCategory::Staged),
+            "unstaged"   => Ok(StatusCategory::Unstaged),
+            "untracked"  => Ok(StatusCategory::Untracked),
+            "conflicted" => Ok(StatusCategory::Conflicted),
+            "renamed"    => Ok(StatusCategory::Renamed),
+            "deleted"    => Ok(StatusCategory::Deleted),
+            _            => Err(format!("invalid status category '{}'", s)),
+        }
+    }
+}
+
+impl Display for StatusCategory {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        let name = match self {
+            StatusCategory::Staged     => "staged",
+            StatusCategory::Unstaged   => "unstaged",
+            StatusCategory::Untracked  => "untracked",
+            StatusCategory::Conflicted => "conflicted",
+            StatusCategory::Renamed    => "renamed",
+            StatusCategory::Deleted    => "deleted",
+        };
+        write!(f, "{}", name)
     }
 }
+// @formatter:on
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Deserialize, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    NdJson,
+}
+
+// @formatter:off
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text"   => Ok(OutputFormat::Text),
+            "json"   => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::NdJson),
+            _        => Err(format!("invalid output format '{}' (expected text, json or ndjson)", s)),
+        }
+    }
+}
+// @formatter:on
+
+/// Machine-readable representation of a single `Project`. Field names and
+/// types are part of the `--format json|ndjson` schema and should only be
+/// extended, never renamed or removed.
+#[derive(Serialize)]
+struct ProjectRecord<'a> {
+    group: &'a str,
+    name: &'a str,
+    path: &'a str,
+    current_branch: &'a str,
+    default_branch: Option<&'a str>,
+    detached: bool,
+    bare: bool,
+    state: Option<&'a str>,
+    upstream: Option<&'a str>,
+    clean: bool,
+    modified: usize,
+    status: StatusRecord,
+    stashes: usize,
+    ahead: usize,
+    behind: usize,
+    remote_ahead_behind: BTreeMap<&'a str, AheadBehindRecord>,
+    local_branches: BTreeMap<&'a str, usize>,
+    fast_forwarded: bool,
+    pushed: bool,
+    timed_out: bool,
+    changes: &'a [String],
+    time: u64,
+}
+
+#[derive(Serialize)]
+struct StatusRecord {
+    staged: usize,
+    unstaged: usize,
+    untracked: usize,
+    conflicted: usize,
+    renamed: usize,
+    deleted: usize,
+}
+
+impl From<&StatusCounts> for StatusRecord {
+    fn from(status: &StatusCounts) -> Self {
+        StatusRecord {
+            staged: status.staged,
+            unstaged: status.unstaged,
+            untracked: status.untracked,
+            conflicted: status.conflicted,
+            renamed: status.renamed,
+            deleted: status.deleted,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AheadBehindRecord {
+    ahead: usize,
+    behind: usize,
+}
+
+impl<'a> From<&'a Project> for ProjectRecord<'a> {
+    fn from(p: &'a Project) -> Self {
+        ProjectRecord {
+            group: &p.grp_name,
+            name: &p.name,
+            path: &p.path,
+            current_branch: &p.current_branch,
+            default_branch: p.default_branch.as_deref(),
+            detached: p.detached,
+            bare: p.bare,
+            state: if p.state.is_empty() { None } else { Some(&p.state) },
+            upstream: p.upstream.as_deref(),
+            clean: p.is_clean(),
+            modified: p.modified,
+            status: StatusRecord::from(&p.status),
+            stashes: p.stashes,
+            ahead: p.ahead_behind.0,
+            behind: p.ahead_behind.1,
+            remote_ahead_behind: p.remote_ahead_behind.iter()
+                .map(|(k, v)| (k.as_str(), AheadBehindRecord { ahead: v.0, behind: v.1 }))
+                .collect(),
+            local_branches: p.local_branches.iter()
+                .map(|(k, v)| (k.as_str(), *v))
+                .collect(),
+            fast_forwarded: p.fast_forwarded,
+            pushed: p.pushed,
+            timed_out: p.timed_out,
+            changes: &p.changes,
+            time: p.time,
+        }
+    }
+}
+
+/// Machine-readable representation of a branch listed by `--stale`.
+#[derive(Serialize)]
+struct StaleRecord<'a> {
+    group: &'a str,
+    name: &'a str,
+    path: &'a str,
+    branch: &'a str,
+    merged: bool,
+    gone: bool,
+    /// Time of the branch's last commit in seconds since the epoch.
+    time: i64,
+}
+
+impl<'a> StaleRecord<'a> {
+    fn new(p: &'a Project, branch: &'a StaleBranch) -> Self {
+        StaleRecord {
+            group: &p.grp_name,
+            name: &p.name,
+            path: &p.path,
+            branch: &branch.name,
+            merged: branch.merged,
+            gone: branch.gone,
+            time: branch.time.seconds(),
+        }
+    }
+}
+
+pub fn print_projects(langs: &[Group], summary_type: &SummaryType, output_types: &[OutputType], sort: &SortType, filters: &[StatusCategory], format: &OutputFormat) {
+    if *format != OutputFormat::Text {
+        return json_print(langs, output_types, sort, filters, *format == OutputFormat::NdJson);
+    }
 
-pub fn print_projects(langs: &Vec<Group>, summary_type: &SummaryType, output_types: &Vec<OutputType>, sort: &SortType) {
     match summary_type {
         SummaryType::VeryVerbose => very_verbose_print(langs),
-        SummaryType::Verbose => verbose_print(langs, output_types, sort),
-        _ => summary_print(langs, output_types, sort),
+        SummaryType::Verbose => verbose_print(langs, output_types, sort, filters),
+        _ => summary_print(langs, output_types, sort, filters),
+    }
+}
+
+/// Prints the branches found by `--stale` grouped by repository.
+pub fn print_stale(stale: &[(&Project, &[StaleBranch])], format: &OutputFormat) {
+    if *format != OutputFormat::Text {
+        let records = stale.iter()
+            .flat_map(|(p, branches)| branches.iter().map(move |branch| StaleRecord::new(p, branch)))
+            .collect::<Vec<StaleRecord>>();
+        if *format == OutputFormat::NdJson {
+            for record in &records {
+                println!("{}", serde_json::to_string(record).unwrap());
+            }
+        } else {
+            println!("{}", serde_json::to_string(&records).unwrap());
+        }
+        return;
+    }
+
+    let grp_maxlen = stale.iter().map(|(p, _)| p.grp_name.len()).max().unwrap_or(0);
+    let proj_maxlen = stale.iter().map(|(p, _)| p.name.len()).max().unwrap_or(0);
+    let branch_maxlen = stale.iter()
+        .flat_map(|(_, branches)| branches.iter().map(|branch| branch.name.len()))
+        .max()
+        .unwrap_or(0);
+
+    for (p, branches) in stale {
+        for branch in branches.iter() {
+            let state = match (branch.merged, branch.gone) {
+                (true, true) => "merged, gone",
+                (true, false) => "merged",
+                _ => "gone",
+            };
+            let time = DateTime::from_timestamp(branch.time.seconds(), 0).unwrap_or_default();
+            println!("{:grp$} {:proj$} {:branch$} {:12} {}",
+                     p.grp_name.color(COLOR_FG), p.name, branch.name.color(COLOR_BRANCH), state.color(COLOR_DIRTY),
+                     time.format("%Y-%m-%d").to_string().black(),
+                     grp = grp_maxlen, proj = proj_maxlen, branch = branch_maxlen);
+        }
     }
 }
 
@@ -171,34 +434,62 @@ fn filter_stub(_: &&Project) -> bool {
 }
 
 fn filter_modification(p: &&Project) -> bool {
-    !p.is_clean() || p.fast_forwarded
+    !p.is_clean() || p.fast_forwarded || p.pushed || p.timed_out
 }
 
 fn print_stub(_: &Project) {}
 
 fn print_branch_stub(_: &Project, _: usize) {}
 
+/// Returns the non-zero per-category counts as symbol prefixed strings paired
+/// with their colors.
+fn status_parts(status: &StatusCounts) -> Vec<(String, &'static str)> {
+    [
+        (SYMBOL_STAGED, status.staged, COLOR_STAGED),
+        (SYMBOL_UNSTAGED, status.unstaged, COLOR_DIRTY),
+        (SYMBOL_UNTRACKED, status.untracked, COLOR_UNTRACKED),
+        (SYMBOL_CONFLICTED, status.conflicted, COLOR_CONFLICTED),
+        (SYMBOL_RENAMED, status.renamed, COLOR_DIRTY),
+        (SYMBOL_DELETED, status.deleted, COLOR_DIRTY),
+    ].iter()
+        .filter(|(_, count, _)| *count > 0)
+        .map(|(symbol, count, color)| (format!("{}{}", symbol, count), *color))
+        .collect()
+}
+
+fn status_len(status: &StatusCounts) -> usize {
+    let parts = status_parts(status);
+    parts.iter().map(|(part, _)| part.chars().count()).sum::<usize>() + parts.len()
+}
+
 fn print_default(p: &Project, grp_len: usize, proj_len: usize, branch_len: usize) {
     let color = match p.is_clean() {
         true => COLOR_CLEAN,
         false => COLOR_DIRTY
     };
-    let pull_flag = if p.fast_forwarded {
+    let pull_flag = if p.timed_out {
+        SYMBOL_TIMED_OUT.color(COLOR_CONFLICTED)
+    } else if p.fast_forwarded {
         SYMBOL_FF.color(COLOR_BEHIND)
+    } else if p.pushed {
+        SYMBOL_PUSH.color(COLOR_AHEAD)
     } else {
         " ".color(COLOR_BEHIND)
     };
     let p_name = p.name.color(color);
     let g_name = p.grp_name.color(COLOR_FG);
-    let branch = format!("{:size$} ", p.current_branch, size = branch_len).color(COLOR_BRANCH);
+    let branch_color = if p.state.is_empty() { COLOR_BRANCH } else { COLOR_CONFLICTED };
+    let branch = format!("{:size$} ", p.head_label(), size = branch_len).color(branch_color);
     print!("{:grp$} {:proj$}{}{}", g_name, p_name, pull_flag, branch, grp = grp_len, proj = proj_len);
 }
 
-fn print_modification(p: &Project) {
+fn print_modification(p: &Project, status_len: usize) {
     let ahead_behind = if p.is_ahead_behind() {
         let ahead = format!("{}{:3}", SYMBOL_AHEAD, p.ahead_behind.0).color(COLOR_AHEAD);
         let behind = format!("{}{:3}", SYMBOL_BEHIND, p.ahead_behind.1).color(COLOR_BEHIND);
         format!("{:4} {:4}", ahead, behind)
+    } else if p.upstream.is_none() && !p.detached && !p.current_branch.is_empty() && !p.bare {
+        format!("{} no upstream", SYMBOL_NO_UPSTREAM).color(COLOR_BEHIND).to_string()
     } else {
         String::new()
     };
@@ -207,14 +498,39 @@ fn print_modification(p: &Project) {
         true => COLOR_DIRTY,
         false => COLOR_CLEAN,
     };
+    // bare repositories have no working tree to be modified
+    let modified = match p.bare {
+        true => String::from(LABEL_BARE),
+        false => format!("{}{}", SYMBOL_MOD, p.modified),
+    };
 
-    print!("{:5} {:9} ", format!("{}{}", SYMBOL_MOD, p.modified).color(color), ahead_behind);
+    let mut status = String::new();
+    let mut len = 0;
+    for (part, color) in status_parts(&p.status) {
+        len += part.chars().count() + 1;
+        status += format!("{} ", part.color(color)).as_str();
+    }
+    status += " ".repeat(status_len - len).as_str();
+
+    let stashes = if p.stashes > 0 {
+        format!("{}{:<3}", SYMBOL_STASH, p.stashes).color(COLOR_STASH)
+    } else {
+        "    ".normal()
+    };
+
+    print!("{:5} {} {}{:9} ", modified.color(color), stashes, status, ahead_behind);
 }
 
 fn print_dir(p: &Project, _: usize, _: usize, _: usize) {
     print!("{}", p.path)
 }
 
+fn print_changes(p: &Project) {
+    if !p.changes.is_empty() {
+        print!("{}", p.changes.join(", ").color(COLOR_DIRTY));
+    }
+}
+
 fn print_extra(p: &Project) {
     let time = p.time.to_string() + "ms";
     print!("{:5}", time.black());
@@ -223,36 +539,89 @@ fn print_extra(p: &Project) {
 fn print_branches(p: &Project, maxlen: usize) {
     for key in p.remote_ahead_behind.keys() {
         // Do not duplicate showing current remote/branch combination twice
-        if *key != p.current_branch && *key != format!("origin/{}", p.current_branch) {
+        if *key != p.current_branch && Some(key) != p.upstream.as_ref() {
             let ahead_behind = p.remote_ahead_behind.get(key).unwrap();
             if ahead_behind.0 > 0 || ahead_behind.1 > 0 {
                 let ahead = format!("{}{:3}", SYMBOL_AHEAD, ahead_behind.0).color(COLOR_AHEAD);
                 let behind = format!("{}{:3}", SYMBOL_BEHIND, ahead_behind.1).color(COLOR_BEHIND);
                 let ahead_behind_str = format!("{:4} {:4}", ahead, behind);
-                let branch = format!("{}", key).color(COLOR_BRANCH);
+                let branch = key.to_string().color(COLOR_BRANCH);
                 print!("{:size$} {} ", branch, ahead_behind_str, size = maxlen);
             }
         }
     }
+
+    // Branches that exist only locally are shown with the number of commits
+    // that would be lost together with the repository.
+    let mut local_branches = p.local_branches.iter().collect::<Vec<(&String, &usize)>>();
+    local_branches.sort();
+    for (branch, count) in local_branches {
+        let unpushed = format!("{} {}{:3}", SYMBOL_NO_UPSTREAM, SYMBOL_AHEAD, count).color(COLOR_AHEAD);
+        let branch = branch.color(COLOR_BRANCH);
+        print!("{:size$} {} ", branch, unpushed, size = maxlen);
+    }
+}
+
+
+/// Collects projects from all groups that should be shown with the given
+/// output types, in the requested order.
+fn select_projects(langs: &[Group], out_types: &[OutputType], sort: &SortType, filters: &[StatusCategory]) -> Vec<Project> {
+    let filter: fn(&&Project) -> bool = if out_types.contains(&OutputType::All) {
+        filter_stub
+    } else {
+        filter_modification
+    };
+
+    let mut projs: Vec<Project> = langs.iter()
+        .flat_map(|l| l.projs.to_vec())
+        .filter(|p| filter(&p))
+        .filter(|p| filters.is_empty() || filters.iter().any(|c| c.count(&p.status) > 0))
+        .collect();
+
+    if let SortType::Status(category) = sort {
+        projs.sort_by_key(|p| category.count(&p.status));
+    } else if *sort != SortType::None { // @formatter:off
+        let sort_fn: fn(&Project, &Project) -> Ordering = match sort {
+            SortType::Dir         => sort_dir,
+            SortType::Time        => sort_time,
+            SortType::Mod         => sort_modification,
+            SortType::AheadBehind => sort_ahead_behind,
+            _                     => sort_default,
+        };
+        projs.sort_by(sort_fn);
+    } // @formatter:on
+
+    projs
 }
 
+fn json_print(langs: &[Group], out_types: &[OutputType], sort: &SortType, filters: &[StatusCategory], ndjson: bool) {
+    let projs = select_projects(langs, out_types, sort, filters);
+    let records = projs.iter()
+        .map(ProjectRecord::from)
+        .collect::<Vec<ProjectRecord>>();
 
-fn summary_print(langs: &Vec<Group>, out_types: &Vec<OutputType>, sort: &SortType) {
+    if ndjson {
+        for record in &records {
+            println!("{}", serde_json::to_string(record).unwrap());
+        }
+    } else {
+        println!("{}", serde_json::to_string(&records).unwrap());
+    }
+}
+
+fn summary_print(langs: &[Group], out_types: &[OutputType], sort: &SortType, filters: &[StatusCategory]) {
     let mut print_fn: fn(&Project, usize, usize, usize) = print_default;
-    let mut print_modification_fn: fn(&Project) = print_stub;
+    let mut print_modification_fn: fn(&Project, usize) = print_branch_stub;
     let mut print_extra_fn: fn(&Project) = print_stub;
     let mut print_branches_fn: fn(&Project, usize) = print_branch_stub;
-    let mut filter: fn(&&Project) -> bool = filter_modification;
 
     // out_types contain only unique values anyways
     for out_type in out_types {
         match out_type {
-            OutputType::All => {
-                filter = filter_stub;
-            }
+            OutputType::All => {}
             OutputType::Dir => {
                 print_fn = print_dir;
-                print_modification_fn = print_stub;
+                print_modification_fn = print_branch_stub;
                 print_extra_fn = print_stub;
                 print_branches_fn = print_branch_stub;
             }
@@ -274,15 +643,15 @@ fn summary_print(langs: &Vec<Group>, out_types: &Vec<OutputType>, sort: &SortTyp
         }
     }
 
-    let mut projs: Vec<Project> = langs.iter()
-        .flat_map(|l| l.projs.to_vec())
-        .filter(|p| filter(&p))
-        .collect();
+    let projs = select_projects(langs, out_types, sort, filters);
 
     let mut grp_maxlen = 0;
     let mut proj_maxlen = 0;
     let mut branch_maxlen = 0;
+    let mut status_maxlen = 0;
     for proj in &projs {
+        status_maxlen = status_maxlen.max(status_len(&proj.status));
+
         if proj.grp_name.len() > grp_maxlen {
             grp_maxlen = proj.grp_name.len();
         }
@@ -291,15 +660,15 @@ fn summary_print(langs: &Vec<Group>, out_types: &Vec<OutputType>, sort: &SortTyp
             proj_maxlen = proj.name.len();
         }
 
-        if proj.current_branch.len() > branch_maxlen {
-            branch_maxlen = proj.current_branch.len();
+        branch_maxlen = branch_maxlen.max(proj.head_label().chars().count());
+
+        for branch in proj.local_branches.keys() {
+            branch_maxlen = branch_maxlen.max(branch.len());
         }
 
         for branch in &proj.branches {
             if branch.len() > branch_maxlen {
-                let ahead_behind = proj.remote_ahead_behind.get(branch.as_str());
-                if ahead_behind.is_some(){
-                    let ahead_behind = ahead_behind.unwrap();
+                if let Some(ahead_behind) = proj.remote_ahead_behind.get(branch.as_str()) {
                     if ahead_behind.0 > 0 || ahead_behind.1 > 0 {
                         branch_maxlen = branch.len();
                     }
@@ -308,31 +677,20 @@ fn summary_print(langs: &Vec<Group>, out_types: &Vec<OutputType>, sort: &SortTyp
         }
     }
 
-
-    if *sort != SortType::None { // @formatter:off
-        let sort_fn: fn(&Project, &Project) -> Ordering = match sort {
-            SortType::Dir         => sort_dir,
-            SortType::Time        => sort_time,
-            SortType::Mod         => sort_modification,
-            SortType::AheadBehind => sort_ahead_behind,
-            _                     => sort_default,
-        };
-        projs.sort_by(sort_fn);
-    } // @formatter:on
-
     for p in &projs {
         print_fn(p, grp_maxlen, proj_maxlen, branch_maxlen);
-        print_modification_fn(p);
+        print_modification_fn(p, status_maxlen);
         print_extra_fn(p);
         print_branches_fn(p, branch_maxlen);
-        print!("\n");
+        print_changes(p);
+        println!();
     }
 }
 
 
-fn verbose_print(langs: &Vec<Group>, out_types: &Vec<OutputType>, sort: &SortType) {
+fn verbose_print(langs: &[Group], out_types: &[OutputType], sort: &SortType, filters: &[StatusCategory]) {
     for l in langs {
-        if l.projs.len() > 0 {
+        if !l.projs.is_empty() {
             let time = out_types.iter().find(|o| o == &&OutputType::Time).is_some();
             let g_name = &l.name;
             let g_projs = l.projs.len().to_string();
@@ -344,10 +702,10 @@ fn verbose_print(langs: &Vec<Group>, out_types: &Vec<OutputType>, sort: &SortTyp
             println!("{:8} {:4} {:6} {}", g_name, g_projs.color(COLOR_CLEAN), time.color("black"), l.path.color("white"));
         }
     }
-    summary_print(langs, out_types, sort);
+    summary_print(langs, out_types, sort, filters);
 }
 
-fn very_verbose_print(langs: &Vec<Group>) {
+fn very_verbose_print(langs: &[Group]) {
     for (i, l) in langs.iter().enumerate() {
         if i == langs.len() - 1 {
             println!("└──{} {}", l.name.color(COLOR_FG), format!("({})", l.projs.len()).black());
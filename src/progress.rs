@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use colored::*;
+use crate::git::FetchEvent;
+
+/// How often the live display is redrawn.
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// Maximum number of in-flight repositories shown below the counter.
+const MAX_ACTIVE_LINES: usize = 5;
+
+/// State of the live display currently on screen, if any, so that prompts
+/// can take over the terminal.
+static DISPLAY: Mutex<Option<Arc<Mutex<State>>>> = Mutex::new(None);
+
+/// Prompts currently waiting for the user and when the last one finished.
+/// Fetches are blocked while prompting, which isn't counted as inactivity.
+static PROMPTS: Mutex<Prompts> = Mutex::new(Prompts { active: 0, finished: None });
+
+struct Prompts {
+    active: usize,
+    finished: Option<Instant>,
+}
+
+struct Active {
+    start: Instant,
+    bytes: usize,
+    message: String,
+}
+
+#[derive(Default)]
+struct State {
+    total: usize,
+    done: usize,
+    bytes: usize,
+    active: HashMap<String, Active>,
+    drawn: usize,
+    finished: bool,
+    suspended: bool,
+}
+
+impl State {
+    fn clear(&mut self) {
+        let mut stderr = io::stderr();
+        for _ in 0..self.drawn {
+            write!(stderr, "\x1b[1A\x1b[2K");
+        }
+        self.drawn = 0;
+    }
+
+    fn draw(&mut self) {
+        self.clear();
+        let received = self.bytes + self.active.values().map(|a| a.bytes).sum::<usize>();
+        let mut lines = vec![format!("fetched {}/{} repositories, {} received", self.done, self.total, human_bytes(received))];
+
+        let mut active = self.active.iter().collect::<Vec<_>>();
+        active.sort_by_key(|(_, a)| a.start);
+        for (path, a) in active.iter().take(MAX_ACTIVE_LINES) {
+            let line = format!("  {} {}s {} {}", path, a.start.elapsed().as_secs(), human_bytes(a.bytes), a.message);
+            lines.push(line.dimmed().to_string());
+        }
+        if active.len() > MAX_ACTIVE_LINES {
+            lines.push(format!("  ... and {} more", active.len() - MAX_ACTIVE_LINES).dimmed().to_string());
+        }
+
+        let mut stderr = io::stderr();
+        for line in &lines {
+            writeln!(stderr, "{}", line);
+        }
+        self.drawn = lines.len();
+    }
+}
+
+/// Multi-line fetch progress shared between the fetch jobs. When stderr is
+/// not a terminal only plain start and result lines are printed.
+#[derive(Clone)]
+pub struct FetchProgress {
+    state: Arc<Mutex<State>>,
+    redraw: Arc<Mutex<Option<JoinHandle<()>>>>,
+    live: bool,
+}
+
+impl FetchProgress {
+    pub fn new(total: usize) -> Self {
+        let state = Arc::new(Mutex::new(State { total, ..Default::default() }));
+        let live = io::stderr().is_terminal();
+        let redraw = if live {
+            let state = Arc::clone(&state);
+            Some(thread::spawn(move || loop {
+                thread::sleep(REDRAW_INTERVAL);
+                let mut state = state.lock().unwrap();
+                if state.finished {
+                    break;
+                }
+                if !state.suspended {
+                    state.draw();
+                }
+            }))
+        } else {
+            None
+        };
+        if live {
+            *DISPLAY.lock().unwrap() = Some(Arc::clone(&state));
+        }
+
+        FetchProgress { state, redraw: Arc::new(Mutex::new(redraw)), live }
+    }
+
+    pub fn start(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        if self.live {
+            state.active.insert(String::from(path), Active { start: Instant::now(), bytes: 0, message: String::new() });
+        } else {
+            eprintln!("{}", format!("fetching {}", path).green());
+        }
+    }
+
+    pub fn update(&self, path: &str, event: FetchEvent) {
+        if !self.live {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(active) = state.active.get_mut(path) {
+            match event {
+                FetchEvent::Transfer { objects, total_objects, bytes } => {
+                    active.bytes = bytes;
+                    if total_objects > 0 {
+                        active.message = format!("{}/{} objects", objects, total_objects);
+                    }
+                }
+                FetchEvent::Sideband(msg) => active.message = String::from(msg),
+                FetchEvent::Idle => {}
+            }
+        }
+    }
+
+    pub fn finish(&self, path: &str, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        // a fetch abandoned after its timeout may still finish later
+        if state.active.remove(path).is_none() && self.live {
+            return;
+        }
+        state.done += 1;
+        state.bytes += bytes;
+    }
+
+    /// Prints a line above the live display. Nothing is printed once the
+    /// display is done.
+    pub fn println(&self, msg: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return;
+        }
+        state.clear();
+        eprintln!("{}", msg);
+    }
+
+    /// Stops redrawing and removes the live display.
+    pub fn done(&self) {
+        DISPLAY.lock().unwrap().take();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.finished = true;
+            state.clear();
+        }
+        if let Some(handle) = self.redraw.lock().unwrap().take() {
+            handle.join();
+        }
+    }
+}
+
+/// Runs `f`, which waits for the user, pausing the fetch timeouts meanwhile.
+#[cfg(unix)]
+pub fn prompt<T>(f: impl FnOnce() -> T) -> T {
+    PROMPTS.lock().unwrap().active += 1;
+    let result = f();
+    let mut prompts = PROMPTS.lock().unwrap();
+    prompts.active -= 1;
+    prompts.finished = Some(Instant::now());
+    result
+}
+
+/// Time a fetch that last made progress at `last` has been inactive, not
+/// counting time spent in prompts.
+pub fn idle_time(last: Instant) -> Duration {
+    let prompts = PROMPTS.lock().unwrap();
+    if prompts.active > 0 {
+        return Duration::ZERO;
+    }
+    match prompts.finished {
+        Some(finished) if finished > last => finished.elapsed(),
+        _ => last.elapsed(),
+    }
+}
+
+/// Removes the live display, if any, while `f` runs so that it can use the
+/// terminal, e.g. to prompt for a passphrase.
+#[cfg(unix)]
+pub fn suspend_display<T>(f: impl FnOnce() -> T) -> T {
+    let display = DISPLAY.lock().unwrap().clone();
+    if let Some(state) = &display {
+        let mut state = state.lock().unwrap();
+        state.suspended = true;
+        state.clear();
+    }
+    let result = prompt(f);
+    if let Some(state) = &display {
+        state.lock().unwrap().suspended = false;
+    }
+    result
+}
+
+/// Formats a byte count using binary units, e.g. `1.5 MiB`.
+pub fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
@@ -1,20 +1,25 @@
-use glob::{GlobResult};
 use mpsc::Sender;
-use savefile::prelude::*;
-use std::ops::Sub;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::sync::mpsc;
-use std::time::{Instant, SystemTime, Duration};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, Duration};
 use std::{fs, io, thread, process};
 use threadpool::ThreadPool;
 
+use crate::cache::{self, Cache, CachedDir, CachedRepo};
+use crate::codeignore::CodeIgnore;
+use crate::history::{self, RepoStatus, Snapshot};
 use crate::lang::{Group, Project};
-use crate::print::{OutputType, print_projects};
+use crate::print::{OutputType, SummaryType, print_projects, print_stale};
+use crate::progress::{FetchProgress, REDRAW_INTERVAL, human_bytes, idle_time};
+use git2::ErrorCode;
 use crate::rgs_opt::RgsOpt;
 use std::fmt::{Display, Formatter};
-use chrono::{NaiveDateTime};
+use chrono::DateTime;
 use crate::git;
+use crate::git::{GitDirKind, StatusCounts};
 use crate::notify::notify;
 use colored::*;
 
@@ -37,11 +42,141 @@ impl From<&str> for RgsError {
     }
 }
 
+/// Exit code bits used by `--check`. A clean run exits with 0, otherwise the
+/// exit code is a combination of the states found across all repositories.
+pub const CHECK_DIRTY: i32 = 4;
+pub const CHECK_AHEAD: i32 = 8;
+pub const CHECK_BEHIND: i32 = 16;
+
+/// Time a fetch job may go without progress beyond `--fetch-timeout` before
+/// the watchdog gives up on it.
+const WATCHDOG_GRACE: Duration = Duration::from_secs(1);
+
+/// Directories that are never searched for repositories as they are big and
+/// only contain build output or dependencies. They are still listed if they
+/// are repositories themselves.
+const SKIPPED_DIRS: [&str; 9] = [".git", "node_modules", "target", "vendor", "venv", ".venv", "__pycache__", ".gradle", ".tox"];
+
+/// A repository found in `path`, followed by its linked worktrees and, with
+/// `submodules`, its checked out submodules. Their group is set once they are
+/// added to one.
+fn repo_projects(path: &Path, submodules: bool) -> Vec<Project> {
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let mut projs = vec![Project::new(name, path.to_str().unwrap(), "")];
+    for (wt_name, worktree) in git::worktrees(path) {
+        projs.push(Project::new(&format!("{}/{}", name, wt_name), worktree.to_str().unwrap(), ""));
+    }
+    if submodules {
+        add_submodules(&mut projs, name, path);
+    }
+    projs
+}
+
+fn add_submodules(projs: &mut Vec<Project>, name: &str, path: &Path) {
+    for submodule in git::submodules(path) {
+        let sub_name = format!("{}/{}", name, submodule.to_str().unwrap());
+        let sub_path = path.join(&submodule);
+        projs.push(Project::new(&sub_name, sub_path.to_str().unwrap(), ""));
+        add_submodules(projs, &sub_name, &sub_path);
+    }
+}
+
+/// State of a fetch job shared with the watchdog in `fetch_projs`.
+struct FetchJob {
+    /// Time of the last progress, or of the next attempt while waiting
+    /// for it.
+    activity: Instant,
+    /// Attempt being made, starting at 0.
+    attempt: u32,
+    /// Set once the watchdog gave up on the job, which then isn't retried
+    /// by the job itself anymore.
+    abandoned: bool,
+}
+
+type JobState = Arc<Mutex<FetchJob>>;
+
+fn job_state(attempt: u32, start: Instant) -> JobState {
+    Arc::new(Mutex::new(FetchJob { activity: start, attempt, abandoned: false }))
+}
+
+/// Messages sent from fetch jobs back to `fetch_projs`.
+enum FetchMsg {
+    Started(usize, usize, JobState),
+    Done(usize, usize, JobState, git::FetchStats, bool),
+}
+
+/// Fetches all remotes of `path`, cancelling an attempt once the remote
+/// hasn't sent anything for `timeout` and retrying failed attempts with
+/// exponential backoff. Returns the last result and whether it was
+/// cancelled due to the timeout.
+fn fetch_with_retries(path: &str, settings: &git::FetchSettings, timeout: Option<Duration>, retries: u32, progress: &FetchProgress, job: &JobState) -> (Result<git::FetchStats, git2::Error>, bool) {
+    loop {
+        let attempt = {
+            let mut job = job.lock().unwrap();
+            job.activity = Instant::now();
+            job.attempt
+        };
+        let mut timed_out = false;
+        let result = git::fetch_all(path, settings, |event| {
+            let mut job = job.lock().unwrap();
+            timed_out = timeout.is_some_and(|timeout| idle_time(job.activity) > timeout);
+            if !matches!(event, git::FetchEvent::Idle) {
+                progress.update(path, event);
+                job.activity = Instant::now();
+            }
+            !timed_out
+        });
+
+        match result {
+            Err(err) if attempt < retries && err.code() != ErrorCode::Auth => {
+                let delay = fetch_backoff(attempt);
+                {
+                    let mut job = job.lock().unwrap();
+                    if job.abandoned {
+                        return (Err(err), timed_out);
+                    }
+                    // waiting for the next attempt isn't inactivity
+                    job.activity = Instant::now() + delay;
+                    job.attempt += 1;
+                }
+                let msg = format!("retrying {} in {}s - {}", path, delay.as_secs(), if timed_out { "timed out" } else { err.message() });
+                progress.println(&msg.yellow().to_string());
+                thread::sleep(delay);
+            }
+            result => return (result, timed_out),
+        }
+    }
+}
+
+/// Delay before retrying a fetch for the `attempt`-th time.
+fn fetch_backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(5))
+}
+
 pub struct Rgs {
     opts: RgsOpt,
     groups: Vec<Group>,
     count: i32,
     pool: ThreadPool,
+    /// `.codeignore` rules applying to the directory being scanned.
+    codeignore: CodeIgnore,
+    /// Paths of all repositories found so far.
+    found: HashSet<String>,
+    /// Linked worktrees found while scanning a root along with their main
+    /// repository.
+    worktrees: Vec<(PathBuf, Option<PathBuf>)>,
+    /// Repositories found while scanning a root and the index of their
+    /// group, see `add_found_repos`.
+    found_repos: Vec<(usize, PathBuf)>,
+    /// Canonical paths of the directories scanned in the current root, so
+    /// that symlinks pointing back up the tree aren't followed forever.
+    visited: HashSet<PathBuf>,
+    /// Cache of the root being scanned as it was read and as it is being
+    /// rebuilt from the entries that are still valid.
+    cache: Cache,
+    next_cache: Cache,
+    /// Whether any entry had to be searched again.
+    cache_changed: bool,
 }
 
 impl Rgs {
@@ -52,26 +187,67 @@ impl Rgs {
             count: 0,
             groups: vec![],
             pool: ThreadPool::new(threads),
+            codeignore: CodeIgnore::default(),
+            found: HashSet::new(),
+            worktrees: vec![],
+            found_repos: vec![],
+            visited: HashSet::new(),
+            cache: Cache::default(),
+            next_cache: Cache::default(),
+            cache_changed: false,
         }
     }
 
     fn validate_code(&self) -> Result<(), RgsError> {
-        if self.opts.code.is_empty() {
+        if self.opts.roots.is_empty() {
             return Err(RgsError::from("'CODE' env variable is not set"));
         }
 
-        if !Path::new(&self.opts.code).exists() {
-            return Err(RgsError::from(format!("{}: no such file or directory", self.opts.code).as_str()));
+        for root in &self.opts.roots {
+            if !Path::new(&root.path).exists() {
+                return Err(RgsError::from(format!("{}: no such file or directory", root.path).as_str()));
+            }
         }
         Ok(())
     }
 
+    /// Label used to tell groups of different roots apart, the root's
+    /// directory name or its full path if several roots share the name.
+    /// Nothing is added when scanning a single root.
+    fn root_label(&self, root: usize) -> Option<String> {
+        if self.opts.roots.len() < 2 {
+            return None;
+        }
+        let path = Path::new(&self.opts.roots[root].path);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let ambiguous = self.opts.roots.iter()
+            .filter(|other| Path::new(&other.path).file_name().and_then(|n| n.to_str()) == Some(name))
+            .count() > 1;
+        if name.is_empty() || ambiguous {
+            Some(self.opts.roots[root].path.clone())
+        } else {
+            Some(String::from(name))
+        }
+    }
+
+    fn group_name(&self, root: usize, name: &str) -> String {
+        match self.root_label(root) {
+            Some(label) => format!("{}/{}", label, name),
+            None => String::from(name),
+        }
+    }
+
     pub fn run(&mut self) -> Result<(), RgsError> {
         self.validate_code()?;
         if self.opts.print_code {
-            println!("{}", self.opts.code);
+            for root in &self.opts.roots {
+                println!("{}", root.path);
+            }
         } else if self.opts.watch {
             self.run_watch()?
+        } else if self.opts.stale {
+            self.load_repos();
+            self.run_stale();
         } else {
             self.load_repos();
 
@@ -79,7 +255,8 @@ impl Rgs {
                 self.fetch_projs();
             }
 
-            if !self.is_showing_only_all_dirs() {
+            let update = !self.is_showing_only_all_dirs() || self.opts.check || self.opts.push || self.opts.diff;
+            if update {
                 self.update_projs();
             }
 
@@ -89,6 +266,14 @@ impl Rgs {
                 self.fast_forward_projs();
             }
 
+            if self.opts.push {
+                self.push_projs();
+            }
+
+            if update {
+                self.record_status();
+            }
+
             self.print();
 
             if self.opts.notify {
@@ -114,62 +299,60 @@ impl Rgs {
             println!("{}:{}\n", repo.to_str().unwrap(), branch);
         }
 
-        let commits_opt = git::behind_commits(&repo.to_str().unwrap(), branch);
-        match commits_opt {
-            Ok(commits) => {
-                if !commits.is_empty() {
-                    let commits_len = commits.len();
-                    let mut notify_body = String::from(format!("{}:{} ({})\n\n", repo.to_str().unwrap(), branch, commits_len));
-                    let mut processed_commits = 0;
-                    for commit in commits {
-                        if processed_commits < MAX_COMMITS_BODY {
-                            let time = NaiveDateTime::from_timestamp(commit.time.seconds(), 0);
-                            if self.opts.watch {
-                                println!("{}\n{}\n{} @ {}\n", commit.id, commit.summary, commit.author, time.format("%Y-%m-%d %H:%M:%S"));
-                            }
-                            let commit_abbr: String = commit.id.chars().take(8).collect();
-                            let summary_abbr: String = commit.summary.chars().take(SUMMARY_ABBR_LEN).collect();
-                            let dots = if commit.summary.len() > SUMMARY_ABBR_LEN {
-                                "..."
-                            } else {
-                                ""
-                            };
-
-                            notify_body += format!("{} {}{}\n", commit_abbr, summary_abbr, dots).as_str();
-                            processed_commits += 1;
-                        } else if processed_commits == MAX_COMMITS_BODY {
-                            let more_commits_msg = format!("\n{} more commit(s)...", commits_len - processed_commits);
-                            notify_body += more_commits_msg.as_str();
-                            if self.opts.watch {
-                                println!("{}", more_commits_msg);
-                            }
-                            processed_commits += 1;
+        let commits_opt = git::behind_commits(repo.to_str().unwrap(), branch);
+        if let Ok(commits) = commits_opt {
+            if !commits.is_empty() {
+                let commits_len = commits.len();
+                let mut notify_body = format!("{}:{} ({})\n\n", repo.to_str().unwrap(), branch, commits_len);
+                let mut processed_commits = 0;
+                for commit in commits {
+                    if processed_commits < MAX_COMMITS_BODY {
+                        let time = DateTime::from_timestamp(commit.time.seconds(), 0).unwrap_or_default();
+                        if self.opts.watch {
+                            println!("{}\n{}\n{} @ {}\n", commit.id, commit.summary, commit.author, time.format("%Y-%m-%d %H:%M:%S"));
+                        }
+                        let commit_abbr: String = commit.id.chars().take(8).collect();
+                        let summary_abbr: String = commit.summary.chars().take(SUMMARY_ABBR_LEN).collect();
+                        let dots = if commit.summary.len() > SUMMARY_ABBR_LEN {
+                            "..."
                         } else {
-                            break;
+                            ""
+                        };
+
+                        notify_body += format!("{} {}{}\n", commit_abbr, summary_abbr, dots).as_str();
+                        processed_commits += 1;
+                    } else if processed_commits == MAX_COMMITS_BODY {
+                        let more_commits_msg = format!("\n{} more commit(s)...", commits_len - processed_commits);
+                        notify_body += more_commits_msg.as_str();
+                        if self.opts.watch {
+                            println!("{}", more_commits_msg);
                         }
+                        processed_commits += 1;
+                    } else {
+                        break;
                     }
+                }
 
-                    if self.opts.notify {
-                        let repo = PathBuf::from(repo);
-                        self.pool.execute(move || {
-                            notify(&repo, &notify_body);
-                        });
-                    }
+                if self.opts.notify {
+                    let repo = PathBuf::from(repo);
+                    self.pool.execute(move || {
+                        notify(&repo, &notify_body);
+                    });
+                }
 
-                    if self.opts.exit {
-                        self.pool.join();
-                        process::exit(commits_len as i32);
-                    }
+                if self.opts.exit {
+                    self.pool.join();
+                    process::exit(commits_len as i32);
                 }
             }
-            Err(_) => {}
         }
     }
 
     fn run_watch(&mut self) -> Result<(), RgsError> {
+        let timeout = self.opts.fetch_timeout;
         // check only once if there is an invalid repository
         for repo in &self.opts.repos {
-            if !git::is_inside_work_tree(&repo.to_str().unwrap()) {
+            if !git::is_inside_work_tree(repo.to_str().unwrap()) {
                 return Err(RgsError::from(format!("'{}': not a valid repository", repo.to_str().unwrap()).as_str()));
             }
         }
@@ -177,11 +360,23 @@ impl Rgs {
         loop {
             for repo in &self.opts.repos {
                 let branch = git::current_branch_from_path(repo).unwrap_or_default();
-                let fetch = git::fetch(repo.to_str().unwrap(), &String::from("origin"), &[&branch]);
-                if fetch.is_ok() {
-                    self.parse_and_notify(repo, &branch);
-                } else if !fetch.is_ok() && self.opts.repos.len() == 1 {
-                    return Err(RgsError::from(fetch.unwrap_err().message()));
+                let remote = git::upstream_remote(repo, &branch).unwrap_or_else(|| String::from("origin"));
+                let mut last = Instant::now();
+                let msg = format!("fetching {}:{}", repo.to_str().unwrap(), remote);
+                eprintln!("{}", msg.green());
+                let active = |event: git::FetchEvent| {
+                    let active = timeout.is_none_or(|t| idle_time(last) < t);
+                    if !matches!(event, git::FetchEvent::Idle) {
+                        last = Instant::now();
+                    }
+                    active
+                };
+                match git::fetch(repo.to_str().unwrap(), &remote, &[&branch], &self.opts.fetch_settings, active) {
+                    Ok(_) => self.parse_and_notify(repo, &branch),
+                    Err(err) if self.opts.repos.len() == 1 => {
+                        return Err(RgsError::from(err.message()));
+                    }
+                    Err(_) => {}
                 }
             }
 
@@ -189,84 +384,317 @@ impl Rgs {
         }
     }
 
+    /// Computes the `--check` exit code from the state of all loaded
+    /// repositories.
+    pub fn check_status(&self) -> i32 {
+        let mut status = 0;
+        for proj in self.groups.iter().flat_map(|g| g.projs.iter()) {
+            if proj.modified > 0 || (proj.stash_dirty && proj.stashes > 0) {
+                status |= CHECK_DIRTY;
+            }
+            if proj.is_ahead() {
+                status |= CHECK_AHEAD;
+            }
+            if proj.is_behind() {
+                status |= CHECK_BEHIND;
+            }
+        }
+        status
+    }
+
+    /// Lists merged and gone branches of all repositories and optionally
+    /// deletes the merged ones.
+    fn run_stale(&mut self) {
+        let (tx, rx) = channel();
+
+        for i in 0..self.groups.len() {
+            for j in 0..self.groups[i].projs.len() {
+                let path = String::from(&self.groups[i].projs[j].path);
+                let tx = Sender::clone(&tx);
+                self.pool.execute(move || {
+                    match git::stale_branches(&path) {
+                        Ok(stale) => tx.send((i, j, stale)).unwrap(),
+                        Err(err) => {
+                            let err_msg = format!("error listing branches {} - {}", path, err.message());
+                            eprintln!("{}", err_msg.red());
+                        }
+                    }
+                });
+            }
+        }
+
+        drop(tx);
+
+        let mut stale = rx.iter()
+            .filter(|(_, _, branches)| !branches.is_empty())
+            .collect::<Vec<(usize, usize, Vec<git::StaleBranch>)>>();
+        self.pool.join();
+        stale.sort_by_key(|(i, j, _)| (*i, *j));
+
+        let listing = stale.iter()
+            .map(|(i, j, branches)| (&self.groups[*i].projs[*j], branches.as_slice()))
+            .collect::<Vec<(&Project, &[git::StaleBranch])>>();
+        print_stale(&listing, &self.opts.format);
+
+        let merged = listing.iter()
+            .flat_map(|(proj, branches)| branches.iter()
+                .filter(|branch| branch.merged)
+                .map(move |branch| (proj.path.clone(), branch.name.clone())))
+            .collect::<Vec<(String, String)>>();
+
+        if !self.opts.prune_merged || merged.is_empty() {
+            return;
+        }
+
+        eprint!("delete {} merged branch(es)? [y/N] ", merged.len());
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
+            return;
+        }
+
+        for (path, branch) in merged {
+            match git::delete_branch(&path, &branch) {
+                Ok(_) => eprintln!("{}", format!("deleted {}:{}", path, branch).green()),
+                Err(err) => eprintln!("{}", format!("error deleting {}:{} - {}", path, branch, err.message()).red()),
+            }
+        }
+    }
+
     #[inline]
     fn is_showing_only_all_dirs(&self) -> bool {
         self.opts.out_types.contains(&OutputType::Dir) && self.opts.out_types.contains(&OutputType::All)
     }
 
     pub fn load_repos(&mut self) {
-        let cache = Path::new(&self.opts.code).join(".codecache");
-        if self.is_showing_only_all_dirs() && cache.exists() {
-            if let Ok(meta) = cache.metadata() {
-                if let Ok(mod_time) = meta.modified() {
-                    if mod_time > SystemTime::now().sub(Duration::from_secs(1800)) {
-                        self.groups = load_file(cache.to_str().unwrap(), 0).unwrap();
-                        return;
-                    }
-                }
-            }
+        for root in 0..self.opts.roots.len() {
+            self.load_root(root);
         }
 
-        match self.list_dir(String::from(&self.opts.code), self.opts.depth) {
+        self.groups.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Loads the repositories of a single root. Only directories and
+    /// repositories that changed since the root's cache was written are
+    /// searched again.
+    fn load_root(&mut self, root: usize) {
+        let code = self.opts.roots[root].path.clone();
+        self.cache = match self.opts.no_cache || self.opts.refresh_cache {
+            true => Cache::new(&code, self.opts.submodules),
+            false => Cache::load(&code, self.opts.submodules),
+        };
+        self.next_cache = Cache::new(&code, self.opts.submodules);
+        self.cache_changed = false;
+
+        self.codeignore = self.opts.roots[root].codeignore.clone();
+        self.visited.clear();
+        match self.list_dir(root, code.clone(), self.opts.depth) {
             Ok(_) => {}
-            Err(err) => { eprintln!("cgs: error: {}", err.to_string()) }
+            Err(err) => { eprintln!("cgs: error: {}", err) }
         }
+        self.add_found_repos();
 
-        let paths = self.opts.codeignore_exclude
-            .iter()
-            .map(|p| String::from(&self.opts.code) + p.as_str())
-            .map(|p| glob::glob(p.as_str()).unwrap())
-            .flat_map(|g| g.into_iter())
-            .collect::<Vec<GlobResult>>();
-        let paths = paths
-            .iter()
-            .map(|r| r.as_ref().unwrap().to_str().unwrap())
-            .collect::<Vec<&str>>();
+        for (path, main) in std::mem::take(&mut self.worktrees) {
+            let found = main.is_some_and(|main| self.found.contains(main.to_str().unwrap()));
+            if !found {
+                self.add_repo(root, &path);
+            }
+        }
+        self.add_found_repos();
 
-        for path in paths {
-            self.process_possible_git_dir(Path::new(path), 1);
+        // entries that are gone from the tree are dropped with the old cache
+        let changed = self.cache_changed
+            || self.next_cache.dirs.len() != self.cache.dirs.len()
+            || self.next_cache.repos.len() != self.cache.repos.len();
+        if changed && !self.opts.no_cache {
+            if let Err(err) = self.next_cache.save() {
+                eprintln!("cgs: error writing {}: {}", cache::cache_path(&code).display(), err);
+            }
         }
+    }
 
-        self.groups.sort_by(|a, b| a.name.cmp(&b.name));
+    pub fn print(&mut self) {
+        if !self.opts.diff {
+            return print_projects(&self.groups, &self.opts.summary_type, &self.opts.out_types, &self.opts.sort, &self.opts.filter, &self.opts.format);
+        }
 
-        // save cache only if it has loaded all the repositories
-        if self.opts.codeignore.len() == 0 {
-            save_file(cache.to_str().unwrap(), 0, &self.groups).unwrap();
+        let mut groups = self.groups.clone();
+        for grp in &mut groups {
+            grp.projs.retain(|p| !p.changes.is_empty());
         }
+        groups.retain(|grp| !grp.projs.is_empty());
+        print_projects(&groups, &self.opts.summary_type, &self.opts.out_types, &self.opts.sort, &self.opts.filter, &self.opts.format)
     }
 
-    pub fn print(&mut self) {
-        print_projects(&self.groups, &self.opts.summary_type, &self.opts.out_types, &self.opts.sort)
+    /// Compares the status of every repository with the previous run and
+    /// saves it for the next one.
+    fn record_status(&mut self) {
+        let mut snapshot = Snapshot::load();
+        if self.opts.diff {
+            match &snapshot {
+                Some(prev) => {
+                    for proj in self.groups.iter_mut().flat_map(|g| g.projs.iter_mut()) {
+                        proj.changes = history::changes(prev.repos.get(&proj.path), &RepoStatus::from(&*proj));
+                    }
+                    let time = DateTime::from_timestamp(prev.time as i64, 0).unwrap_or_default();
+                    let msg = format!("changes since {} UTC", time.format("%Y-%m-%d %H:%M:%S"));
+                    eprintln!("{}", msg.black());
+                }
+                None => eprintln!("cgs: no previous run to compare with"),
+            }
+        }
+
+        let snapshot = snapshot.get_or_insert_with(Snapshot::default);
+        snapshot.update(self.groups.iter().flat_map(|g| g.projs.iter()));
+        if let Err(err) = snapshot.save() {
+            eprintln!("cgs: error saving status: {}", err);
+        }
     }
 
     pub fn fetch_projs(&mut self) {
         let (tx, rx) = channel();
+        let progress = FetchProgress::new(self.groups.iter().map(|g| g.projs.len()).sum());
+        // Fetches run on their own pool so that a job stuck on an unresponsive
+        // remote can be abandoned without blocking later `pool.join()` calls.
+        let mut pool = ThreadPool::new(self.opts.threads);
+        let timeout = self.opts.fetch_timeout;
+        let retries = self.opts.fetch_retries;
+        let settings = self.opts.fetch_settings;
+        let fetch = |i: usize, j: usize, path: String, job: JobState, delay: Duration| {
+            let tx = Sender::clone(&tx);
+            let progress = progress.clone();
+            move || {
+                thread::sleep(delay);
+                tx.send(FetchMsg::Started(i, j, Arc::clone(&job))).unwrap();
+                progress.start(&path);
+                let (result, timed_out) = fetch_with_retries(&path, &settings, timeout, retries, &progress, &job);
+                if job.lock().unwrap().abandoned {
+                    return;
+                }
+                let stats = match result {
+                    Ok(stats) => {
+                        if stats.updated > 0 || stats.pruned > 0 {
+                            let msg = format!("fetched {} - {} updated, {} pruned", path, stats.updated, stats.pruned);
+                            progress.println(&msg.green().to_string());
+                        }
+                        stats
+                    }
+                    Err(_) if timed_out => {
+                        let err_msg = format!("timed out fetching {}", path);
+                        progress.println(&err_msg.red().to_string());
+                        git::FetchStats::default()
+                    }
+                    Err(err) => {
+                        let err_msg = format!("error fetching {} - {}", path, err.message());
+                        progress.println(&err_msg.red().to_string());
+                        git::FetchStats::default()
+                    }
+                };
+                progress.finish(&path, stats.bytes);
+                tx.send(FetchMsg::Done(i, j, job, stats, timed_out)).unwrap_or_default()
+            }
+        };
 
+        // the latest job of every repository, older ones have been abandoned
+        let mut jobs: HashMap<(usize, usize), JobState> = HashMap::new();
         for i in 0..self.groups.len() {
             for j in 0..self.groups[i].projs.len() {
                 let path = String::from(&self.groups[i].projs[j].path);
-                // let branch = String::from(&self.groups[i].projs[j].current_branch);
-                let tx = Sender::clone(&tx);
-                self.pool.execute(move || {
-                    let now = Instant::now();
-                    let res = git::fetch_all(&path);
-                    if res.is_err() {
-                        let err_msg = format!("error fetching {} - {}", path, res.err().unwrap().message());
-                        eprintln!("{}", err_msg.red());
-                    }
-                    tx.send((i, j, now.elapsed().as_millis() as u64)).unwrap()
-                });
+                let job = job_state(0, Instant::now());
+                jobs.insert((i, j), Arc::clone(&job));
+                pool.execute(fetch(i, j, path, job, Duration::ZERO));
             }
         }
 
-        drop(tx);
+        // Watchdog for fetches that stop reaching the progress callback, e.g.
+        // when connecting to a host behind a dead VPN. The grace period lets
+        // the jobs cancel themselves first. A job given up on is retried by
+        // a new one, its pool thread is replaced as it may never return.
+        let deadline = timeout.map(|timeout| timeout + WATCHDOG_GRACE);
+        let mut started: HashMap<(usize, usize), Instant> = HashMap::new();
+        let mut abandoned = false;
+        while !jobs.is_empty() {
+            let msg = match deadline {
+                Some(_) => rx.recv_timeout(REDRAW_INTERVAL),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let current = |i, j, job: &JobState| jobs.get(&(i, j)).is_some_and(|current| Arc::ptr_eq(current, job));
+            match msg {
+                Ok(FetchMsg::Started(i, j, job)) if current(i, j, &job) => {
+                    started.entry((i, j)).or_insert_with(Instant::now);
+                }
+                Ok(FetchMsg::Done(i, j, job, stats, timed_out)) if current(i, j, &job) => {
+                    jobs.remove(&(i, j));
+                    let proj = &mut self.groups[i].projs[j];
+                    proj.fetch_stats = stats;
+                    proj.timed_out = timed_out;
+                    proj.time += started.remove(&(i, j)).map(|start| start.elapsed().as_millis() as u64).unwrap_or_default();
+                }
+                // from a job that was given up on
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
 
-        for (i, j, time) in rx {
-            let proj = &mut self.groups[i].projs[j];
-            proj.time += time;
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => continue,
+            };
+            let expired = jobs.iter()
+                .filter(|(key, _)| started.contains_key(key))
+                .filter(|(_, job)| idle_time(job.lock().unwrap().activity) > deadline)
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            for (i, j) in expired {
+                abandoned = true;
+                let attempt = {
+                    let mut job = jobs[&(i, j)].lock().unwrap();
+                    job.abandoned = true;
+                    job.attempt
+                };
+                let path = self.groups[i].projs[j].path.clone();
+                if attempt < retries {
+                    let delay = fetch_backoff(attempt);
+                    let msg = format!("retrying {} in {}s - timed out", path, delay.as_secs());
+                    progress.println(&msg.yellow().to_string());
+                    let job = job_state(attempt + 1, Instant::now() + delay);
+                    jobs.insert((i, j), Arc::clone(&job));
+                    pool.set_num_threads(pool.max_count() + 1);
+                    pool.execute(fetch(i, j, path, job, delay));
+                    continue;
+                }
+
+                jobs.remove(&(i, j));
+                let err_msg = format!("timed out fetching {}", path);
+                progress.println(&err_msg.red().to_string());
+                progress.finish(&path, 0);
+                let proj = &mut self.groups[i].projs[j];
+                proj.timed_out = true;
+                proj.time += started.remove(&(i, j)).map(|start| start.elapsed().as_millis() as u64).unwrap_or_default();
+            }
         }
 
-        self.pool.join();
+        if !abandoned {
+            pool.join();
+        }
+        progress.done();
+
+        if self.opts.summary_type != SummaryType::Default {
+            self.print_fetch_summary();
+        }
+    }
+
+    /// Prints objects and bytes received per repository.
+    fn print_fetch_summary(&self) {
+        let projs = self.groups.iter().flat_map(|g| g.projs.iter()).collect::<Vec<_>>();
+        let path_len = projs.iter().map(|p| p.path.len()).max().unwrap_or(0);
+        let (mut objects, mut bytes) = (0, 0);
+        for p in &projs {
+            eprintln!("{:<path_len$} {:>8} objects {:>10}", p.path, p.fetch_stats.objects, human_bytes(p.fetch_stats.bytes), path_len = path_len);
+            objects += p.fetch_stats.objects;
+            bytes += p.fetch_stats.bytes;
+        }
+        let msg = format!("fetched {} objects, {} from {} repositories", objects, human_bytes(bytes), projs.len());
+        eprintln!("{}", msg.green());
     }
 
     pub fn update_projs(&mut self) {
@@ -275,19 +703,41 @@ impl Rgs {
         for i in 0..self.groups.len() {
             for j in 0..self.groups[i].projs.len() {
                 let path = String::from(&self.groups[i].projs[j].path);
-                let branch = String::from(&self.groups[i].projs[j].current_branch);
                 let branches = self.opts.branches;
+                let untracked = self.opts.untracked;
                 let tx = Sender::clone(&tx);
                 self.pool.execute(move || {
                     let now = Instant::now();
-                    let modified = git::is_clean(&path);
-                    let ahead_behind = git::ahead_behind(&path, &branch).unwrap_or((0, 0));
-                    let ahead_behind_remote = if branches {
-                        git::ahead_behind_remote(&path).unwrap_or(vec![])
+                    let bare = git::is_bare(&path);
+                    let (branch, detached) = git::head(&path);
+                    // branches may have changed since the repository was cached
+                    let local = git::branches(&path);
+                    let default_branch = git::default_branch(&path);
+                    // bare repositories have no working tree to check
+                    let (state, (modified, status), stashes) = if bare {
+                        (String::new(), (0, StatusCounts::default()), 0)
+                    } else {
+                        (git::state(&path), git::status(&path, untracked), git::stash_count(&path))
+                    };
+                    let upstream = if detached { None } else { git::upstream(&path, &branch) };
+                    let upstream_remote = upstream.as_ref().and_then(|_| git::upstream_remote(&path, &branch));
+                    let ahead_behind = upstream.as_ref()
+                        .and_then(|upstream| git::ahead_behind(&path, upstream).ok())
+                        .unwrap_or((0, 0));
+                    // all branches of a bare repository are compared with the
+                    // remote as they are what it keeps track of
+                    let ahead_behind_remote = if branches || bare {
+                        git::ahead_behind_remote(&path).unwrap_or_default()
+                    } else {
+                        vec![]
+                    };
+                    // every branch of a mirror would be local only
+                    let local_branches = if branches && !bare {
+                        git::local_only_branches(&path).unwrap_or_default()
                     } else {
                         vec![]
                     };
-                    tx.send((i, j, modified, ahead_behind, ahead_behind_remote, now.elapsed().as_millis() as u64)).unwrap();
+                    tx.send((i, j, (branch, local, default_branch, detached, state, bare), modified, status, stashes, (upstream, upstream_remote), ahead_behind, ahead_behind_remote, local_branches, now.elapsed().as_millis() as u64)).unwrap();
                 });
             }
         }
@@ -295,125 +745,343 @@ impl Rgs {
         drop(tx);
         self.pool.join();
 
-        for (i, j, modified, ahead_behind, ahead_behind_remote, time) in rx {
+        for (i, j, (branch, local, default_branch, detached, state, bare), modified, status, stashes, (upstream, upstream_remote), ahead_behind, ahead_behind_remote, local_branches, time) in rx {
             let proj = &mut self.groups[i].projs[j];
+            proj.branches = local;
+            proj.bare = bare;
+            proj.current_branch = branch;
+            proj.default_branch = default_branch;
+            proj.detached = detached;
+            proj.state = state;
             proj.modified = modified;
+            proj.status = status;
+            proj.stashes = stashes;
+            proj.stash_dirty = self.opts.stash_dirty;
+            proj.upstream = upstream;
+            proj.upstream_remote = upstream_remote;
             proj.ahead_behind = ahead_behind;
             for data in ahead_behind_remote {
                 proj.remote_ahead_behind.insert(format!("{}/{}", data.0, data.1), (data.2, data.3));
             }
+            if proj.bare && proj.upstream.is_none() {
+                proj.ahead_behind = proj.remote_ahead_behind.iter()
+                    .filter(|(key, _)| key.starts_with(format!("{}/", proj.upstream_remote()).as_str()))
+                    .fold((0, 0), |sum, (_, (ahead, behind))| (sum.0 + ahead, sum.1 + behind));
+            }
+            proj.local_branches = local_branches.into_iter().collect();
             proj.time += time;
         }
     }
 
 
-    pub fn list_dir(&mut self, path: String, depth: usize) -> io::Result<()> {
-        if depth == 0 { return Ok(()); }
+    /// Scans `path` for repositories. `None` as depth means no limit.
+    pub fn list_dir(&mut self, root: usize, path: String, depth: Option<usize>) -> io::Result<()> {
+        if depth == Some(0) { return Ok(()); }
+        if !self.visited.insert(fs::canonicalize(&path)?) { return Ok(()); }
 
-        for entry in fs::read_dir(path)? {
-            self.process_possible_git_dir(&entry?.path(), depth);
+        // the root's own .codeignore is already loaded
+        let rules = if self.opts.no_ignore || path == self.opts.roots[root].path {
+            None
+        } else {
+            Some(self.codeignore.load(Path::new(&path)))
         };
-        Ok(())
+
+        let result = self.subdirs(&path).map(|subdirs| {
+            for subdir in subdirs {
+                self.process_possible_git_dir(root, Path::new(&subdir), depth);
+            }
+        });
+
+        if let Some(rules) = rules {
+            self.codeignore.truncate(rules);
+        }
+        result
     }
 
-    fn process_possible_git_dir(&mut self, path: &Path, depth: usize) {
-        let path_str = path.to_str().unwrap();
-        let replaced = path_str.replace(&self.opts.code, "");
-        let path_root = replaced.as_str();
+    /// Subdirectories of `path` that are not ignored, taken from the cache
+    /// if neither the directory nor the `.codeignore` rules changed.
+    fn subdirs(&mut self, path: &str) -> io::Result<Vec<String>> {
+        let mtime = cache::mtime(&fs::metadata(path)?);
+        let rules = self.codeignore.fingerprint();
+        let cached = self.cache.dir(path, mtime)
+            .filter(|dir| dir.rules == rules)
+            .and_then(|dir| dir.subdirs.clone());
+        let subdirs = match cached {
+            Some(subdirs) => subdirs,
+            None => {
+                self.cache_changed = true;
+                let mut entries = fs::read_dir(path)?.flatten().map(|entry| entry.path()).collect::<Vec<PathBuf>>();
+                entries.sort();
+                entries.into_iter()
+                    .filter(|entry| entry.is_dir() && !self.codeignore.is_ignored(entry, true))
+                    .map(|entry| String::from(entry.to_str().unwrap()))
+                    .collect()
+            }
+        };
+
+        self.next_cache.dirs.insert(String::from(path), CachedDir { mtime, rules, subdirs: Some(subdirs.clone()) });
+        Ok(subdirs)
+    }
 
-        let mut skip = false;
-        if self.opts.codeignore.iter().any(|g| g.matches(path_root)) {
-            skip = true;
+    /// Tells whether `path` is a repository, using the cache where possible.
+    fn dir_kind(&mut self, path: &str) -> Option<GitDirKind> {
+        let mtime = fs::metadata(path).map(|meta| cache::mtime(&meta)).unwrap_or_default();
+        if let Some(repo) = self.cache.repo(path) {
+            let kind = repo.kind.clone();
+            self.next_cache.repos.insert(String::from(path), repo.clone());
+            return Some(kind);
+        }
+        if let Some(dir) = self.cache.dir(path, mtime) {
+            // a directory becoming a repository changes its modification time
+            self.next_cache.dirs.insert(String::from(path), dir.clone());
+            return None;
         }
 
-        if self.opts.codeignore_exclude.iter().any(|g| g.matches(path_root)) {
-            skip = false;
+        self.cache_changed = true;
+        let kind = git::git_dir_kind(Path::new(path));
+        match &kind {
+            Some(kind) => {
+                let stamp = cache::repo_stamp(Path::new(path));
+                self.next_cache.repos.insert(String::from(path), CachedRepo { stamp, kind: kind.clone(), projs: vec![] });
+            }
+            None => {
+                self.next_cache.dirs.insert(String::from(path), CachedDir { mtime, rules: 0, subdirs: None });
+            }
         }
+        kind
+    }
 
-        if skip {
-            return;
+    fn process_possible_git_dir(&mut self, root: usize, path: &Path, depth: Option<usize>) {
+        let code = self.opts.roots[root].path.clone();
+        let path_str = path.to_str().unwrap();
+
+        let dir_name = path.file_name().unwrap().to_str().unwrap();
+        let par_name = path.parent().unwrap().to_str().unwrap();
+
+        match self.dir_kind(path_str) {
+            Some(GitDirKind::Repository) | Some(GitDirKind::Bare) => self.add_repo(root, path),
+            // worktrees are listed along with their main repository, only
+            // the ones whose main repository isn't found end up on their own
+            Some(GitDirKind::Worktree(main)) => self.worktrees.push((path.to_path_buf(), main)),
+            None => {
+                if code == par_name {
+                    self.groups.push(Group::new(&self.group_name(root, dir_name), path_str));
+                }
+                if !SKIPPED_DIRS.contains(&dir_name) {
+                    self.list_dir(root, path_str.to_string(), depth.map(|depth| depth - 1));
+                }
+            }
         }
+    }
+
+    /// Queues a repository found in `root` to be added to its group by
+    /// `add_found_repos`.
+    fn add_repo(&mut self, root: usize, path: &Path) {
+        let code = self.opts.roots[root].path.clone();
+        let path_str = path.to_str().unwrap();
+        let par_name = path.parent().unwrap().to_str().unwrap();
+
+        // top-level repositories (eg. uni) are grouped under the root itself
+        let grp_name = if code.as_str() == par_name {
+            self.root_label(root).unwrap_or_else(|| String::from("CODE"))
+        } else {
+            self.group_name(root, Path::new(par_name).strip_prefix(&code).unwrap().to_str().unwrap())
+        };
 
-        if path.is_dir() {
-            let dir_name = path.file_name().unwrap().to_str().unwrap();
-            let par_name = path.parent().unwrap().to_str().unwrap();
+        let i = match self.groups.iter().position(|g| g.name == grp_name) {
+            Some(i) => i,
+            None => {
+                self.groups.push(Group::new(&grp_name, path_str));
+                self.groups.len() - 1
+            }
+        };
 
-            if git::is_inside_work_tree(&path_str) {
+        self.found_repos.push((i, path.to_path_buf()));
+    }
 
-                self.count += 1;
+    /// Adds the queued repositories along with their worktrees and
+    /// submodules. Repositories that are not cached are opened in parallel.
+    fn add_found_repos(&mut self) {
+        let found = std::mem::take(&mut self.found_repos);
+        let (tx, rx) = channel();
 
-                // last or new
-                let mut lang = self.groups.pop().unwrap_or(Group::new(dir_name, path_str));
+        for (n, (_, path)) in found.iter().enumerate() {
+            let cached = self.next_cache.repos.get(path.to_str().unwrap()).is_some_and(|repo| !repo.projs.is_empty());
+            if cached {
+                continue;
+            }
+            let path = path.clone();
+            let submodules = self.opts.submodules;
+            let tx = Sender::clone(&tx);
+            self.pool.execute(move || {
+                tx.send((n, repo_projects(&path, submodules))).unwrap();
+            });
+        }
 
-                // if its a top-level repository (eg. uni)
-                if self.opts.code.as_str() == par_name {
-                    self.groups.push(lang);
-                    lang = Group::new("CODE", path_str);
-                } else {
-                    let code = Path::new(&self.opts.code);
-                    let root = code.join(Path::new(&lang.name));
-                    let root = root.to_str().unwrap();
-                    if root != par_name {
-                        let code_len = code.to_str().unwrap().len() + 1;
-                        let lang_name = &par_name[code_len..];
-                        self.groups.push(lang);
-                        lang = Group::new(&lang_name, path_str);
-                    }
-                }
+        drop(tx);
+        self.pool.join();
 
-                lang.add_project(Project::new(dir_name, path_str, &lang.name.as_str()));
-                self.groups.push(lang);
-            } else {
-                if self.opts.code == par_name {
-                    self.groups.push(Group::new(dir_name, path_str));
+        for (n, projs) in rx {
+            self.cache_changed = true;
+            let path = found[n].1.to_str().unwrap();
+            let stamp = cache::repo_stamp(&found[n].1);
+            let kind = match self.next_cache.repos.get(path) {
+                Some(repo) => repo.kind.clone(),
+                None => GitDirKind::Repository,
+            };
+            self.next_cache.repos.insert(String::from(path), CachedRepo { stamp, kind, projs });
+        }
+
+        for (i, path) in found {
+            let projs = self.next_cache.repos.get(path.to_str().unwrap()).map(|repo| repo.projs.clone()).unwrap_or_default();
+            for proj in projs {
+                // worktrees may have been removed without telling git
+                if Path::new(&proj.path).exists() {
+                    self.add_project(i, proj);
                 }
-                self.list_dir(path_str.to_string(), depth - 1);
             }
         }
     }
 
+    fn add_project(&mut self, i: usize, mut proj: Project) {
+        if !self.found.insert(proj.path.clone()) {
+            return;
+        }
+        self.count += 1;
+        proj.grp_name = self.groups[i].name.clone();
+        self.groups[i].add_project(proj);
+    }
+
     fn fast_forward_projs(&mut self) {
         let (tx, rx) = channel();
 
         for i in 0..self.groups.len() {
             for j in 0..self.groups[i].projs.len() {
                 let proj = &self.groups[i].projs[j];
-                if proj.modified != 0 {
+                if proj.modified != 0 || proj.bare {
                     continue;
                 }
 
                 let path = String::from(&proj.path);
+                let fast_forward_all = self.opts.fast_forward_all;
                 let tx = Sender::clone(&tx);
                 let p = proj.clone();
                 self.pool.execute(move || {
                     let now = Instant::now();
-                    let mut success = true;
-                    for ahead_behind in p.remote_ahead_behind.iter() {
-                        let res = git::fast_forward(&path, &ahead_behind.0);
-                        if res.is_err() {
-                            let err_msg = format!("error fast-forwarding {}:{} - {}", path, ahead_behind.0, res.as_ref().err().unwrap().message());
+                    // by default only the default branch is fast-forwarded,
+                    // it isn't known yet if the status hasn't been checked
+                    let targets = if fast_forward_all {
+                        p.branches.clone()
+                    } else {
+                        p.default_branch.clone().or_else(|| git::default_branch(&path)).into_iter().collect()
+                    };
+                    let mut forwarded = false;
+                    let mut current_forwarded = false;
+                    // only branches that track something can be fast-forwarded
+                    for branch in targets.iter().filter(|b| git::upstream(&path, b).is_some()) {
+                        match git::fast_forward(&path, branch) {
+                            Ok(moved) => {
+                                forwarded |= moved;
+                                current_forwarded |= moved && *branch == p.current_branch;
+                            }
+                            Err(err) => {
+                                let err_msg = format!("error fast-forwarding {}:{} - {}", path, branch, err.message());
+                                eprintln!("{}", err_msg.red());
+                            }
+                        }
+                    }
+                    tx.send((i, j, now.elapsed().as_millis() as u64, forwarded, current_forwarded)).unwrap()
+                });
+            }
+        }
+
+        drop(tx);
+
+        for (i, j, time, forwarded, current_forwarded) in rx {
+            let proj = &mut self.groups[i].projs[j];
+            proj.fast_forwarded = forwarded;
+
+            // if ff of the checked out branch was successful we manually set
+            // behind commits to zero
+            if current_forwarded {
+                proj.ahead_behind.1 = 0
+            }
+            proj.time += time;
+        }
+
+        self.pool.join();
+    }
+
+    /// Pushes the current branch of every repository that is ahead of its
+    /// remote and reports the result for each of them.
+    fn push_projs(&mut self) {
+        let (tx, rx) = channel();
+        let dry_run = self.opts.dry_run;
+
+        for i in 0..self.groups.len() {
+            for j in 0..self.groups[i].projs.len() {
+                let proj = &self.groups[i].projs[j];
+                let upstream = match &proj.upstream {
+                    Some(upstream) if proj.ahead_behind.0 > 0 && !proj.bare => upstream.clone(),
+                    _ => continue,
+                };
+
+                let path = String::from(&proj.path);
+                let branch = String::from(&proj.current_branch);
+                let ahead = proj.ahead_behind.0;
+                let tx = Sender::clone(&tx);
+                self.pool.execute(move || {
+                    let now = Instant::now();
+                    if dry_run {
+                        let msg = format!("would push {}:{} to {} ({} commit(s))", path, branch, upstream, ahead);
+                        eprintln!("{}", msg.green());
+                        tx.send((i, j, now.elapsed().as_millis() as u64, false)).unwrap();
+                        return;
+                    }
+
+                    let res = git::push(&path, &branch);
+                    match &res {
+                        Ok(_) => {
+                            let msg = format!("pushed {}:{} to {} ({} commit(s))", path, branch, upstream, ahead);
+                            eprintln!("{}", msg.green());
+                        }
+                        Err(err) => {
+                            let err_msg = format!("error pushing {}:{} - {}", path, branch, err.message());
                             eprintln!("{}", err_msg.red());
-                            success = false;
                         }
                     }
-                    tx.send((i, j, now.elapsed().as_millis() as u64, success)).unwrap()
+                    tx.send((i, j, now.elapsed().as_millis() as u64, res.is_ok())).unwrap()
                 });
             }
         }
 
         drop(tx);
 
+        let mut pushed = 0;
+        let mut failed = 0;
         for (i, j, time, result) in rx {
             let proj = &mut self.groups[i].projs[j];
-            proj.fast_forwarded = result;
+            proj.pushed = result;
 
-            // if ff was successful we manually set behind commits to zero
+            // same as with fast-forwarding, a successful push leaves nothing ahead
             if result {
-                proj.ahead_behind.1 = 0
+                proj.ahead_behind.0 = 0;
+                pushed += 1;
+            } else if !dry_run {
+                failed += 1;
             }
             proj.time += time;
         }
 
         self.pool.join();
+
+        if !dry_run {
+            let msg = format!("pushed {} repositories, {} failed", pushed, failed);
+            if failed > 0 {
+                eprintln!("{}", msg.red());
+            } else {
+                eprintln!("{}", msg.green());
+            }
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,15 @@
-use glob::Pattern;
-use crate::print::{OutputType, SortType, SummaryType};
-use std::{io, env};
+use crate::codeignore::CodeIgnore;
+use crate::git::{FetchSettings, UntrackedMode};
+use crate::print::{OutputFormat, OutputType, SortType, StatusCategory, SummaryType};
+use std::env;
 use std::collections::HashSet;
-use std::fs::{File};
 use std::path::{Path, PathBuf};
 use std::iter::FromIterator;
-use std::io::{BufRead, Read};
+use std::time::Duration;
 use structopt::StructOpt;
+use structopt::clap::ArgMatches;
 use serde_derive::Deserialize;
-use toml::value::Table;
+use crate::config::{Config, Settings};
 
 #[derive(Debug, StructOpt, Deserialize)]
 #[structopt(name = "rgs",
@@ -16,23 +17,57 @@ about = "Batch repository check tool (github.com/7aske/rgs)",
 author = "Nikola Tasić - 7aske.com",
 version = env!("CARGO_PKG_VERSION"))]
 pub struct RgsOptStruct {
-    #[structopt(short = "c", long = "code", env, help = "override CODE variable")]
-    pub code: String,
-    #[structopt(short = "C", long = "print-code", help = "print CODE variable")]
+    #[structopt(short = "c", long = "code", env, use_delimiter = true, value_delimiter = ":", help = "override CODE variable, can be repeated or colon-separated to scan multiple roots")]
+    pub code: Vec<String>,
+    #[structopt(short = "C", long = "print-code", help = "print CODE variable, one root per line")]
     pub print_code: bool,
     #[structopt(short = "v", long = "verbose", parse(from_occurrences), help = "print additional information")]
     pub verbose: u8,
-    #[structopt(short = "i", long = "no-ignore", help = "don't read .codeignore file")]
+    #[structopt(short = "i", long = "no-ignore", help = "don't read .codeignore files")]
     pub no_ignore: bool,
-    #[structopt(short = "s", long = "sort", parse(from_str), help = "sort by: directory (d), modifications (m), time (t), ahead-behind (a)")]
+    #[structopt(short = "s", long = "sort", parse(from_str), help = "sort by: directory (d), modifications (m), time (t), ahead-behind (a), staged, unstaged, untracked, conflicted, renamed, deleted")]
     pub sort: Option<SortType>,
+    #[structopt(long = "filter", use_delimiter = true, help = "show only repositories with staged, unstaged, untracked, conflicted, renamed or deleted files")]
+    pub filter: Vec<StatusCategory>,
+    #[structopt(long = "untracked", default_value = "config", help = "untracked files handling: config (follow status.showUntrackedFiles), normal, separate (don't count as dirty), no")]
+    pub untracked: UntrackedMode,
+    #[structopt(long = "stash-dirty", help = "treat repositories with stashes as not clean")]
+    pub stash_dirty: bool,
+    #[structopt(short = "o", long = "format", default_value = "text", help = "output format: text, json, ndjson")]
+    pub format: OutputFormat,
     #[structopt(short = "f", long = "fetch", help = "also fetch from origin")]
     pub fetch: bool,
+    #[structopt(long = "prune", help = "remove remote tracking branches that no longer exist on the remote when fetching")]
+    pub prune: bool,
+    #[structopt(long = "tags", help = "fetch all tags from the remote")]
+    pub tags: bool,
+    #[structopt(long = "no-tags", conflicts_with = "tags", help = "don't fetch any tags from the remote")]
+    pub no_tags: bool,
+    #[structopt(long = "fetch-timeout", default_value = "30", help = "seconds without any data from the remote after which a fetch is cancelled, 0 to disable")]
+    pub fetch_timeout: u64,
+    #[structopt(long = "fetch-retries", default_value = "2", help = "number of times a failed fetch is retried with increasing delay")]
+    pub fetch_retries: u32,
     #[structopt(short = "F", long = "ff", help = "also fast-forward default branch")]
     pub fast_forward: bool,
-    #[structopt(short = "D", long = "depth", default_value = "2", help = "project search recursive depth")]
+    #[structopt(long = "ff-all", help = "fast-forward all branches that track an upstream instead of only the default one (assumes -F flag)")]
+    pub fast_forward_all: bool,
+    #[structopt(short = "P", long = "push", help = "push current branches that are ahead of their upstream")]
+    pub push: bool,
+    #[structopt(long = "dry-run", help = "only show what would be pushed")]
+    pub dry_run: bool,
+    #[structopt(long = "stale", help = "list local branches that are merged into the default branch or whose upstream is gone")]
+    pub stale: bool,
+    #[structopt(long = "prune-merged", help = "delete merged branches listed by --stale after confirmation")]
+    pub prune_merged: bool,
+    #[structopt(short = "D", long = "depth", default_value = "2", help = "project search recursive depth, 0 for unlimited")]
     pub depth: usize,
-    #[structopt(short = "p", long = "profile", help = "load profile configuration from 'coderc'")]
+    #[structopt(long = "submodules", help = "also list checked out submodules of found repositories")]
+    pub submodules: bool,
+    #[structopt(long = "refresh-cache", help = "search all directories again instead of using the discovery cache")]
+    pub refresh_cache: bool,
+    #[structopt(long = "no-cache", conflicts_with = "refresh-cache", help = "neither read nor write the discovery cache")]
+    pub no_cache: bool,
+    #[structopt(short = "p", long = "profile", help = "load a profile from the configuration file, default: RGS_PROFILE")]
     pub profile: Option<String>,
 
     #[structopt(short = "j", long = "jobs", help = "number of threads, default: number of logical cpus")]
@@ -46,7 +81,11 @@ pub struct RgsOptStruct {
     pub dir: bool,
     #[structopt(short = "m", long = "mod", help = "show modifications or ahead/behind status")]
     pub modification: bool,
-    #[structopt(short = "b", long = "branches", help = "show remote branch ahead/behind status (assumes -m flag)")]
+    #[structopt(long = "diff", help = "show only repositories whose status changed since the previous run (assumes -a and -m flags)")]
+    pub diff: bool,
+    #[structopt(long = "check", help = "exit with a status code describing repository state: dirty (4), ahead (8), behind (16), combined as bits")]
+    pub check: bool,
+    #[structopt(short = "b", long = "branches", help = "show remote branch ahead/behind status and local-only branches (assumes -m flag)")]
     pub branches: bool,
 
     #[structopt(flatten)]
@@ -70,98 +109,193 @@ pub struct RgsWatchOptStruct {
 
 
 impl RgsOptStruct {
-    fn update_with(&mut self, table: &Table) {
-        if table.contains_key("code") {
-            self.code = String::from(table.get("code").unwrap().as_str().unwrap());
+    /// Fills in every option that wasn't given on the command line from
+    /// `settings`.
+    fn update_with(&mut self, settings: &Settings, matches: &ArgMatches) {
+        let unset = |arg: &str| matches.occurrences_of(arg) == 0;
+
+        if let Some(code) = settings.code.as_ref().filter(|_| unset("code")) {
+            self.code = code.roots();
         }
-        if table.contains_key("no-ignore") {
-            self.no_ignore = table.get("no-ignore").unwrap().as_bool().unwrap();
+        if let Some(print_code) = settings.print_code.filter(|_| unset("print_code")) {
+            self.print_code = print_code;
         }
-
-        if table.contains_key("sort") {
-            self.sort = Some(SortType::from(table.get("sort").unwrap().as_str().unwrap()))
+        if let Some(verbose) = settings.verbose.filter(|_| unset("verbose")) {
+            self.verbose = verbose;
         }
-        if table.contains_key("fetch") {
-            self.fetch = table.get("fetch").unwrap().as_bool().unwrap();
+        if let Some(no_ignore) = settings.no_ignore.filter(|_| unset("no_ignore")) {
+            self.no_ignore = no_ignore;
         }
-        if table.contains_key("depth") {
-            self.depth = table.get("depth").unwrap().as_integer().unwrap() as usize;
+        if let Some(sort) = settings.sort.filter(|_| unset("sort")) {
+            self.sort = Some(sort);
         }
-        if table.contains_key("jobs") {
-            self.threads = Some(table.get("jobs").unwrap().as_integer().unwrap() as usize);
+        if let Some(filter) = settings.filter.as_ref().filter(|_| unset("filter")) {
+            self.filter = filter.clone();
         }
-        if table.contains_key("time") {
-            self.time = table.get("time").unwrap().as_bool().unwrap();
+        if let Some(untracked) = settings.untracked.filter(|_| unset("untracked")) {
+            self.untracked = untracked;
         }
-        if table.contains_key("all") {
-            self.all = table.get("all").unwrap().as_bool().unwrap();
+        if let Some(stash_dirty) = settings.stash_dirty.filter(|_| unset("stash_dirty")) {
+            self.stash_dirty = stash_dirty;
         }
-        if table.contains_key("dir") {
-            self.dir = table.get("dir").unwrap().as_bool().unwrap();
+        if let Some(format) = settings.format.filter(|_| unset("format")) {
+            self.format = format;
         }
-        if table.contains_key("modification") {
-            self.modification = table.get("modification").unwrap().as_bool().unwrap();
-        } else if table.contains_key("mod") {
-            self.modification = table.get("mod").unwrap().as_bool().unwrap();
+        if let Some(fetch) = settings.fetch.filter(|_| unset("fetch")) {
+            self.fetch = fetch;
         }
-        if table.contains_key("branches") {
-            self.branches = table.get("branches").unwrap().as_bool().unwrap();
+        if let Some(prune) = settings.prune.filter(|_| unset("prune")) {
+            self.prune = prune;
+        }
+        if unset("tags") && unset("no_tags") {
+            if let Some(tags) = settings.tags {
+                self.tags = tags;
+                self.no_tags = !tags;
+            }
+            if let Some(no_tags) = settings.no_tags {
+                self.tags = self.tags && !no_tags;
+                self.no_tags = no_tags;
+            }
+        }
+        if let Some(fetch_timeout) = settings.fetch_timeout.filter(|_| unset("fetch_timeout")) {
+            self.fetch_timeout = fetch_timeout;
+        }
+        if let Some(fetch_retries) = settings.fetch_retries.filter(|_| unset("fetch_retries")) {
+            self.fetch_retries = fetch_retries;
+        }
+        if let Some(fast_forward) = settings.fast_forward.filter(|_| unset("fast_forward")) {
+            self.fast_forward = fast_forward;
+        }
+        if let Some(fast_forward_all) = settings.fast_forward_all.filter(|_| unset("fast_forward_all")) {
+            self.fast_forward_all = fast_forward_all;
+        }
+        if let Some(push) = settings.push.filter(|_| unset("push")) {
+            self.push = push;
+        }
+        if let Some(dry_run) = settings.dry_run.filter(|_| unset("dry_run")) {
+            self.dry_run = dry_run;
+        }
+        if let Some(stale) = settings.stale.filter(|_| unset("stale")) {
+            self.stale = stale;
+        }
+        if let Some(prune_merged) = settings.prune_merged.filter(|_| unset("prune_merged")) {
+            self.prune_merged = prune_merged;
+        }
+        if let Some(depth) = settings.depth.filter(|_| unset("depth")) {
+            self.depth = depth;
+        }
+        if let Some(submodules) = settings.submodules.filter(|_| unset("submodules")) {
+            self.submodules = submodules;
+        }
+        if let Some(refresh_cache) = settings.refresh_cache.filter(|_| unset("refresh_cache")) {
+            self.refresh_cache = refresh_cache;
+        }
+        if let Some(no_cache) = settings.no_cache.filter(|_| unset("no_cache")) {
+            self.no_cache = no_cache;
+        }
+        if let Some(jobs) = settings.jobs.filter(|_| unset("threads")) {
+            self.threads = Some(jobs);
+        }
+        if let Some(time) = settings.time.filter(|_| unset("time")) {
+            self.time = time;
+        }
+        if let Some(all) = settings.all.filter(|_| unset("all")) {
+            self.all = all;
+        }
+        if let Some(dir) = settings.dir.filter(|_| unset("dir")) {
+            self.dir = dir;
+        }
+        if let Some(modification) = settings.modification.filter(|_| unset("modification")) {
+            self.modification = modification;
+        }
+        if let Some(diff) = settings.diff.filter(|_| unset("diff")) {
+            self.diff = diff;
+        }
+        if let Some(check) = settings.check.filter(|_| unset("check")) {
+            self.check = check;
+        }
+        if let Some(branches) = settings.branches.filter(|_| unset("branches")) {
+            self.branches = branches;
         }
-    }
 
-    pub fn load_profile(&mut self) {
-        let env_profile = env::var("RGS_PROFILE");
-        // if RGS_PROFILE and cli argument profile is not set we don't have
-        // anything to do.
-        if self.profile.is_none() && env_profile.is_err() {
-            return;
+        let watch = &mut self.watch_options;
+        if let Some(repos) = settings.watch.as_ref().filter(|_| unset("repos")) {
+            watch.repos = repos.clone();
+        }
+        if let Some(timeout) = settings.timeout.filter(|_| unset("timeout")) {
+            watch.timeout = timeout;
         }
+        if let Some(exit) = settings.exit.filter(|_| unset("exit")) {
+            watch.exit = exit;
+        }
+        if let Some(notify) = settings.notify.filter(|_| unset("notify")) {
+            watch.notify = notify;
+        }
+    }
 
-        let profile = if env_profile.is_ok() {
-            env_profile.unwrap()
-        } else {
-            self.profile.clone().unwrap()
+    /// Applies the defaults of the configuration file followed by the
+    /// profile selected with `-p` or `RGS_PROFILE`. Options given on the
+    /// command line take precedence over both.
+    pub fn load_config(&mut self, matches: &ArgMatches) -> Result<(), String> {
+        // an empty RGS_PROFILE selects no profile
+        let profile = self.profile.clone()
+            .or_else(|| env::var("RGS_PROFILE").ok())
+            .filter(|profile| !profile.is_empty());
+
+        let mut config = match Config::load(profile.as_deref())? {
+            Some(config) => config,
+            None => return match profile {
+                Some(profile) => Err(format!("profile '{}' not found, there is no configuration file", profile)),
+                None => Ok(()),
+            },
         };
 
-        // If the RGS_PROFILE is set but it is set to an empty string, we don't
-        // have anything to do also.
-        if profile.is_empty() {
-            return;
+        // the CODE variable takes precedence over the top-level `code`, but
+        // not over the one of a selected profile
+        if env::var_os("CODE").is_some() {
+            config.defaults.code = None;
         }
-
-        let location_config = Path::new(&env::var("HOME").unwrap()).join(".config").join("coderc");
-        let location_home = Path::new(&env::var("HOME").unwrap()).join(".coderc");
-        let mut config_string = String::new();
-        if location_home.exists() {
-            let mut file = File::open(location_home).unwrap();
-            file.read_to_string(&mut config_string);
-        } else if location_config.exists() {
-            let mut file = File::open(location_config).unwrap();
-            file.read_to_string(&mut config_string);
-        }
-
-        let config: toml::Value = toml::from_str(config_string.as_str()).unwrap();
-        let config = config.as_table().unwrap();
-        if config.contains_key(&profile) {
-            let config = config.get(&profile).unwrap().as_table().unwrap();
-            self.update_with(config);
-        } else {
-            eprintln!("cgs: profile '{}' not found", profile);
+        self.update_with(&config.defaults, matches);
+        if let Some(profile) = profile {
+            self.update_with(config.profile(&profile)?, matches);
         }
+        Ok(())
     }
 }
 
+/// A directory scanned for repositories along with the rules of its
+/// `.codeignore`.
+pub struct CodeRoot {
+    pub path: String,
+    pub codeignore: CodeIgnore,
+}
+
 pub struct RgsOpt {
-    pub code: String,
+    pub roots: Vec<CodeRoot>,
+    pub no_ignore: bool,
     pub print_code: bool,
-    pub codeignore: Vec<Pattern>,
-    pub codeignore_exclude: Vec<Pattern>,
     pub out_types: Vec<OutputType>,
     pub sort: SortType,
+    pub format: OutputFormat,
+    pub filter: Vec<StatusCategory>,
+    pub untracked: UntrackedMode,
+    pub stash_dirty: bool,
     pub summary_type: SummaryType,
     pub fetch: bool,
+    pub fetch_settings: FetchSettings,
+    pub fetch_timeout: Option<Duration>,
+    pub fetch_retries: u32,
     pub fast_forward: bool,
-    pub depth: usize,
+    pub fast_forward_all: bool,
+    pub push: bool,
+    pub dry_run: bool,
+    pub stale: bool,
+    pub prune_merged: bool,
+    /// Search depth, `None` when unlimited.
+    pub depth: Option<usize>,
+    pub submodules: bool,
+    pub refresh_cache: bool,
+    pub no_cache: bool,
     pub threads: usize,
 
     pub watch: bool,
@@ -170,46 +304,27 @@ pub struct RgsOpt {
     pub exit: bool,
     pub notify: bool,
     pub branches: bool,
+    pub check: bool,
+    pub diff: bool,
 }
 
-#[inline(always)]
-fn parse_codeignore(code: &String, no_codeignore: bool) -> (Vec<Pattern>, Vec<Pattern>) {
-    if no_codeignore {
-        return (vec![], vec![]);
-    }
-
-    let mut codeignore = vec![];
-    let mut codeignore_exclude = vec![];
-    let file = File::open(Path::new(code).join(".codeignore"));
-    if file.is_ok() {
-        let file = file.unwrap();
-        let lines = io::BufReader::new(file)
-            .lines()
-            .filter_map(|line| line.ok())
-            .filter(|line| !line.starts_with("#"))
-            .collect::<Vec<String>>();
-        for line in lines {
-            if line.starts_with("!") {
-                let line = line.chars().skip(1).collect::<String>();
-                codeignore_exclude.push(Pattern::new(line.as_str()).unwrap());
-            } else  {
-                codeignore.push(Pattern::new(line.as_str()).unwrap());
-            }
-        }
-    };
-
-    return (codeignore, codeignore_exclude);
-}
-
-
 impl From<&RgsOptStruct> for RgsOpt {
     fn from(opt: &RgsOptStruct) -> Self {
-        let code = String::from(&opt.code);
+        let roots = opt.code.iter()
+            .filter(|root| !root.is_empty())
+            // trailing slashes would break matching paths against the root
+            .map(|root| if root.len() > 1 { root.trim_end_matches('/') } else { root.as_str() })
+            .map(|root| {
+                let mut codeignore = CodeIgnore::default();
+                if !opt.no_ignore {
+                    codeignore.load(Path::new(root));
+                }
+                CodeRoot { path: String::from(root), codeignore }
+            })
+            .collect::<Vec<CodeRoot>>();
+        let no_ignore = opt.no_ignore;
         let print_code = opt.print_code;
 
-        let (codeignore, codeignore_exclude) = parse_codeignore(&code, opt.no_ignore);
-        
-
         let mut out_types: HashSet<OutputType> = HashSet::new();
         if opt.all {
             out_types.insert(OutputType::All);
@@ -223,6 +338,11 @@ impl From<&RgsOptStruct> for RgsOpt {
             out_types.insert(OutputType::Modification);
         }
 
+        if opt.diff {
+            out_types.insert(OutputType::All);
+            out_types.insert(OutputType::Modification);
+        }
+
         if opt.dir {
             out_types.insert(OutputType::Dir);
             out_types.retain(|x| *x != OutputType::Modification && *x != OutputType::Time);
@@ -239,23 +359,55 @@ impl From<&RgsOptStruct> for RgsOpt {
         let out_types = Vec::from_iter(out_types);
 
         let sort = opt.sort.unwrap_or_default();
+        let format = opt.format;
+        let filter = opt.filter.clone();
+        let untracked = opt.untracked;
+        let stash_dirty = opt.stash_dirty;
         let fetch = opt.fetch;
-        let fast_forward = opt.fast_forward;
-        let depth = opt.depth;
+        let fetch_settings = FetchSettings {
+            prune: opt.prune,
+            tags: match (opt.tags, opt.no_tags) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+        };
+        let fetch_timeout = match opt.fetch_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let fetch_retries = opt.fetch_retries;
+        let fast_forward = opt.fast_forward || opt.fast_forward_all;
+        let fast_forward_all = opt.fast_forward_all;
+        let push = opt.push;
+        let dry_run = opt.dry_run;
+        let stale = opt.stale;
+        let prune_merged = opt.prune_merged;
+        let depth = match opt.depth {
+            0 => None,
+            depth => Some(depth),
+        };
+        let submodules = opt.submodules;
+        let refresh_cache = opt.refresh_cache;
+        let no_cache = opt.no_cache;
         let branches = opt.branches;
+        let check = opt.check;
+        let diff = opt.diff;
         let threads = opt.threads.unwrap_or(num_cpus::get());
         let summary_type = SummaryType::from_occurrences(opt.verbose as u64);
 
-        let watch = opt.watch_options.repos.len() > 0;
+        let watch = !opt.watch_options.repos.is_empty();
         let repos = opt.watch_options.repos.clone().iter()
             .map(|repo| {
                 let repo_path = PathBuf::from(repo);
 
-                return if repo_path.is_absolute() {
-                    repo_path
-                } else {
-                    Path::new(&code).join(Path::new(repo))
-                };
+                if repo_path.is_absolute() {
+                    return repo_path;
+                }
+                // relative paths are resolved against the first root they
+                // exist in
+                let candidates = roots.iter().map(|root| Path::new(&root.path).join(repo)).collect::<Vec<PathBuf>>();
+                candidates.iter().find(|path| path.exists()).or(candidates.first()).cloned().unwrap_or(repo_path)
             })
             .collect::<Vec<PathBuf>>();
 
@@ -264,16 +416,30 @@ impl From<&RgsOptStruct> for RgsOpt {
         let notify = opt.watch_options.notify;
 
         RgsOpt {
-            code,
+            roots,
+            no_ignore,
             print_code,
-            codeignore,
-            codeignore_exclude,
             summary_type,
             out_types,
             sort,
+            format,
+            filter,
+            untracked,
+            stash_dirty,
             fetch,
+            fetch_settings,
+            fetch_timeout,
+            fetch_retries,
             fast_forward,
+            fast_forward_all,
+            push,
+            dry_run,
+            stale,
+            prune_merged,
             depth,
+            submodules,
+            refresh_cache,
+            no_cache,
             threads,
             watch,
             repos,
@@ -281,6 +447,8 @@ impl From<&RgsOptStruct> for RgsOpt {
             exit,
             notify,
             branches,
+            check,
+            diff,
         }
     }
 }
\ No newline at end of file
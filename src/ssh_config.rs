@@ -0,0 +1,518 @@
+use glob::Pattern;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// Maximum nesting of `Include` directives, same as OpenSSH.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Identity files OpenSSH tries when none are configured.
+const DEFAULT_IDENTITY_FILES: [&str; 6] = [
+    "~/.ssh/id_rsa",
+    "~/.ssh/id_ecdsa",
+    "~/.ssh/id_ecdsa_sk",
+    "~/.ssh/id_ed25519",
+    "~/.ssh/id_ed25519_sk",
+    "~/.ssh/id_dsa",
+];
+
+/// Options of a single host resolved from `ssh_config(5)` files.
+#[derive(Clone, Debug, Default)]
+pub struct SshHost {
+    pub hostname: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Identity files in the order they should be tried.
+    pub identity_files: Vec<PathBuf>,
+    pub identities_only: bool,
+    pub proxy_jump: Option<String>,
+    pub proxy_command: Option<String>,
+}
+
+impl SshHost {
+    /// Whether the host can only be reached through OpenSSH itself, which
+    /// libgit2 doesn't support.
+    pub fn needs_openssh(&self) -> bool {
+        [&self.proxy_jump, &self.proxy_command].iter()
+            .any(|option| option.as_deref().is_some_and(|value| !value.eq_ignore_ascii_case("none")))
+    }
+}
+
+/// Resolves `host` using `~/.ssh/config` followed by `/etc/ssh/ssh_config`.
+/// As in OpenSSH the first value obtained for an option wins, except for
+/// `IdentityFile` of which all values are used.
+pub fn query(host: &str) -> SshHost {
+    let mut resolver = Resolver { host, options: HashMap::new(), identity_files: vec![] };
+    resolver.read_file(&expand_tilde("~/.ssh/config"), &expand_tilde("~/.ssh"), 0);
+    resolver.read_file(Path::new("/etc/ssh/ssh_config"), Path::new("/etc/ssh"), 0);
+    resolver.finish()
+}
+
+struct Resolver<'a> {
+    host: &'a str,
+    options: HashMap<String, String>,
+    identity_files: Vec<String>,
+}
+
+impl Resolver<'_> {
+    fn read_file(&mut self, path: &Path, base: &Path, depth: usize) {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+
+        // Options before the first `Host` line apply to every host.
+        let mut active = true;
+        for line in content.lines() {
+            let (keyword, args) = match split_line(line) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            match keyword.as_str() {
+                "host" => active = host_matches(self.host, args.iter().map(String::as_str)),
+                "match" => active = self.match_matches(&args),
+                "include" if active && depth < MAX_INCLUDE_DEPTH => {
+                    for arg in &args {
+                        for include in include_paths(arg, base) {
+                            self.read_file(&include, base, depth + 1);
+                        }
+                    }
+                }
+                "identityfile" if active => {
+                    self.identity_files.extend(args);
+                }
+                _ if active && !args.is_empty() => {
+                    self.options.entry(keyword).or_insert_with(|| args.join(" "));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Evaluates the criteria of a `Match` line. Criteria that can't be
+    /// evaluated here, like `exec`, never match.
+    fn match_matches(&self, args: &[String]) -> bool {
+        let mut args = args.iter();
+        while let Some(criterion) = args.next() {
+            let criterion = criterion.to_lowercase();
+            let (negate, criterion) = match criterion.strip_prefix('!') {
+                Some(criterion) => (true, criterion),
+                None => (false, criterion.as_str()),
+            };
+            let matched = match criterion {
+                "all" => true,
+                "canonical" | "final" => false,
+                _ => {
+                    let patterns = match args.next() {
+                        Some(patterns) => patterns.split(',').collect::<Vec<_>>(),
+                        None => return false,
+                    };
+                    match criterion {
+                        "host" => host_matches(&self.hostname(), patterns.into_iter()),
+                        "originalhost" => host_matches(self.host, patterns.into_iter()),
+                        "user" => self.options.get("user").is_some_and(|user| host_matches(user, patterns.into_iter())),
+                        "localuser" => host_matches(&local_user(), patterns.into_iter()),
+                        _ => false,
+                    }
+                }
+            };
+            if matched == negate {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn hostname(&self) -> String {
+        match self.options.get("hostname") {
+            Some(hostname) => expand_tokens(hostname, self.host, None),
+            None => String::from(self.host),
+        }
+    }
+
+    fn finish(self) -> SshHost {
+        let hostname = self.hostname();
+        let user = self.options.get("user").cloned();
+        let identity_files = if self.identity_files.is_empty() {
+            DEFAULT_IDENTITY_FILES.iter().map(expand_tilde).collect()
+        } else {
+            self.identity_files.iter()
+                .map(|file| expand_tilde(expand_tokens(file, &hostname, user.as_deref())))
+                .collect()
+        };
+
+        SshHost {
+            port: self.options.get("port").and_then(|port| port.parse().ok()),
+            identities_only: self.options.get("identitiesonly").is_some_and(|value| value.eq_ignore_ascii_case("yes")),
+            proxy_jump: self.options.get("proxyjump").cloned(),
+            proxy_command: self.options.get("proxycommand").cloned(),
+            identity_files,
+            hostname,
+            user,
+        }
+    }
+}
+
+/// Splits a config line into its lower-cased keyword and arguments. Both
+/// `Keyword value` and `Keyword=value` forms are accepted and arguments may
+/// be double quoted.
+fn split_line(line: &str) -> Option<(String, Vec<String>)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let keyword = line[..end].to_lowercase();
+    let rest = line[end..].trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest);
+
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut quoted = false;
+    let mut in_arg = false;
+    for c in rest.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+
+    Some((keyword, args))
+}
+
+/// Matches `host` against `ssh_config` patterns. A matching negated pattern
+/// rejects the host regardless of the other patterns.
+fn host_matches<'a>(host: &str, patterns: impl Iterator<Item=&'a str>) -> bool {
+    let host = host.to_lowercase();
+    let mut matched = false;
+    for pattern in patterns {
+        let (negate, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let is_match = Pattern::new(&pattern.to_lowercase()).is_ok_and(|pattern| pattern.matches(&host));
+        if is_match && negate {
+            return false;
+        }
+        matched |= is_match;
+    }
+    matched
+}
+
+/// Files referenced by an `Include` argument. Relative paths are relative to
+/// the directory of the top level config and may contain globs.
+fn include_paths(arg: &str, base: &Path) -> Vec<PathBuf> {
+    let path = expand_tilde(arg);
+    let path = if path.is_absolute() { path } else { base.join(path) };
+    let mut paths = glob::glob(&path.to_string_lossy())
+        .map(|paths| paths.filter_map(Result::ok).collect::<Vec<_>>())
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+/// Expands the `%h`, `%r`, `%u`, `%d` and `%%` tokens.
+fn expand_tokens(value: &str, host: &str, user: Option<&str>) -> String {
+    let mut expanded = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded += host,
+            Some('r') => expanded += user.map(String::from).unwrap_or_else(local_user).as_str(),
+            Some('u') => expanded += local_user().as_str(),
+            Some('d') => expanded += env::var("HOME").unwrap_or_default().as_str(),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+/// Name of the local user, which OpenSSH uses when no user is configured.
+pub fn local_user() -> String {
+    env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_else(|_| String::from("git"))
+}
+
+pub fn expand_tilde<P: AsRef<Path>>(path_user_input: P) -> PathBuf {
+    let p = path_user_input.as_ref();
+    if !p.starts_with("~") {
+        return p.to_path_buf();
+    }
+
+    let home = env::var("HOME").unwrap_or_default();
+
+    if p == Path::new("~") {
+        return PathBuf::from(home);
+    }
+
+    PathBuf::from(home).join(p.strip_prefix("~").unwrap())
+}
+
+/// Remote URL using the SSH transport, either `ssh://[user@]host[:port]/path`
+/// or the scp-like `[user@]host:path`.
+pub struct SshUrl {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+    scp: bool,
+}
+
+impl SshUrl {
+    pub fn parse(url: &str) -> Option<SshUrl> {
+        for scheme in ["ssh://", "git+ssh://", "ssh+git://"] {
+            if let Some(rest) = url.strip_prefix(scheme) {
+                let (authority, path) = rest.split_at(rest.find('/')?);
+                let (user, host_port) = split_user(authority);
+                let (host, port) = match host_port.strip_prefix('[') {
+                    Some(ipv6) => {
+                        let (host, rest) = ipv6.split_once(']')?;
+                        (host, rest.strip_prefix(':'))
+                    }
+                    None => match host_port.rsplit_once(':') {
+                        Some((host, port)) => (host, Some(port)),
+                        None => (host_port, None),
+                    },
+                };
+                let port = match port {
+                    Some(port) if !port.is_empty() => Some(port.parse().ok()?),
+                    _ => None,
+                };
+                return Some(SshUrl { user, host: String::from(host), port, path: String::from(path), scp: false });
+            }
+        }
+
+        if url.contains("://") {
+            return None;
+        }
+
+        // A slash before the first colon means a local path.
+        let colon = if url.starts_with('[') { url.find("]:")? + 1 } else { url.find(':')? };
+        if url[..colon].contains('/') {
+            return None;
+        }
+        let (user, host) = split_user(&url[..colon]);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Some(SshUrl { user, host: String::from(host), port: None, path: String::from(&url[colon + 1..]), scp: true })
+    }
+
+    /// URL that reaches the resolved `host` directly, or `None` if libgit2
+    /// can't express it. That is the case for a non-default port combined
+    /// with a path relative to the home directory.
+    pub fn resolve(&self, host: &SshHost) -> Option<String> {
+        let user = self.user.as_ref().or(host.user.as_ref())
+            .map(|user| format!("{}@", user))
+            .unwrap_or_default();
+        let hostname = if host.hostname.contains(':') { format!("[{}]", host.hostname) } else { host.hostname.clone() };
+
+        match self.port.or(host.port) {
+            Some(port) if port != 22 => {
+                let path = if !self.scp || self.path.starts_with('/') {
+                    self.path.clone()
+                } else if self.path.starts_with('~') {
+                    format!("/{}", self.path)
+                } else {
+                    return None;
+                };
+                Some(format!("ssh://{}{}:{}{}", user, hostname, port, path))
+            }
+            _ if self.scp => Some(format!("{}{}:{}", user, hostname, self.path)),
+            _ => Some(format!("ssh://{}{}{}", user, hostname, self.path)),
+        }
+    }
+}
+
+fn split_user(authority: &str) -> (Option<String>, &str) {
+    match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(String::from(user)), host),
+        None => (None, authority),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    /// Resolves `host` using only `config`, written to a temporary directory
+    /// along with `extra` files.
+    fn resolve(host: &str, config: &str, extra: &[(&str, &str)]) -> SshHost {
+        let dir = env::temp_dir().join(format!("rgs-ssh-config-{}-{}", std::process::id(), host));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in extra {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        fs::write(dir.join("config"), config).unwrap();
+        let mut resolver = Resolver { host, options: HashMap::new(), identity_files: vec![] };
+        resolver.read_file(&dir.join("config"), &dir, 0);
+        fs::remove_dir_all(&dir).unwrap();
+        resolver.finish()
+    }
+
+    #[test]
+    fn split_line_forms() {
+        assert_eq!(split_line("  HostName example.com"), Some((String::from("hostname"), args(&["example.com"]))));
+        assert_eq!(split_line("Port=2222"), Some((String::from("port"), args(&["2222"]))));
+        assert_eq!(split_line("User = git"), Some((String::from("user"), args(&["git"]))));
+        assert_eq!(split_line("Host a b\t c"), Some((String::from("host"), args(&["a", "b", "c"]))));
+        assert_eq!(split_line("# comment"), None);
+        assert_eq!(split_line("   "), None);
+    }
+
+    #[test]
+    fn split_line_quoting() {
+        assert_eq!(split_line(r#"IdentityFile "~/My Keys/id_rsa""#), Some((String::from("identityfile"), args(&["~/My Keys/id_rsa"]))));
+        assert_eq!(split_line(r#"ProxyCommand "" x"#), Some((String::from("proxycommand"), args(&["", "x"]))));
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(host_matches("github.com", ["*.com"].iter().copied()));
+        assert!(host_matches("GitHub.com", ["github.COM"].iter().copied()));
+        assert!(host_matches("a.example.com", ["other", "*.example.com"].iter().copied()));
+        assert!(!host_matches("github.com", ["gitlab.com"].iter().copied()));
+        assert!(!host_matches("internal.example.com", ["*.example.com", "!internal.*"].iter().copied()));
+        assert!(!host_matches("internal.example.com", ["!internal.*", "*.example.com"].iter().copied()));
+        // a negated pattern alone never matches
+        assert!(!host_matches("github.com", ["!gitlab.com"].iter().copied()));
+    }
+
+    #[test]
+    fn match_criteria() {
+        let mut resolver = Resolver { host: "gh", options: HashMap::new(), identity_files: vec![] };
+        resolver.options.insert(String::from("hostname"), String::from("github.com"));
+        resolver.options.insert(String::from("user"), String::from("git"));
+        assert!(resolver.match_matches(&args(&["all"])));
+        assert!(resolver.match_matches(&args(&["host", "*.com"])));
+        assert!(resolver.match_matches(&args(&["originalhost", "gh"])));
+        assert!(resolver.match_matches(&args(&["Host", "github.com", "user", "git"])));
+        assert!(resolver.match_matches(&args(&["!host", "gitlab.com"])));
+        assert!(!resolver.match_matches(&args(&["!host", "github.com"])));
+        assert!(!resolver.match_matches(&args(&["host", "github.com", "user", "root"])));
+        assert!(!resolver.match_matches(&args(&["exec", "true"])));
+        assert!(!resolver.match_matches(&args(&["host"])));
+    }
+
+    #[test]
+    fn first_value_wins() {
+        let host = resolve("first", "Host first\n  Port 2222\n  User alice\nHost *\n  Port 22\n  User bob\n  HostName %h.example.com\n", &[]);
+        assert_eq!(host.port, Some(2222));
+        assert_eq!(host.user.as_deref(), Some("alice"));
+        assert_eq!(host.hostname, "first.example.com");
+    }
+
+    #[test]
+    fn options_before_host_apply_to_all() {
+        let host = resolve("global", "User everyone\nHost other\n  User nobody\n", &[]);
+        assert_eq!(host.user.as_deref(), Some("everyone"));
+    }
+
+    #[test]
+    fn identity_files_accumulate() {
+        let host = resolve("keys", "Host keys\n  IdentityFile /keys/%h\n  User u\nHost *\n  IdentityFile /keys/%r\n", &[]);
+        assert_eq!(host.identity_files, vec![PathBuf::from("/keys/keys"), PathBuf::from("/keys/u")]);
+    }
+
+    #[test]
+    fn include_globs() {
+        let config = "Include conf.d-*\nHost included\n  Port 1\n";
+        let host = resolve("included", config, &[
+            ("conf.d-b", "Host included\n  User b\n"),
+            ("conf.d-a", "Host included\n  User a\n  HostName real.example.com\n"),
+        ]);
+        // included files are read in sorted order before the rest
+        assert_eq!(host.user.as_deref(), Some("a"));
+        assert_eq!(host.hostname, "real.example.com");
+        assert_eq!(host.port, Some(1));
+    }
+
+    #[test]
+    fn proxy_options() {
+        assert!(resolve("jump", "Host jump\n  ProxyJump bastion\n", &[]).needs_openssh());
+        assert!(!resolve("nojump", "Host nojump\n  ProxyCommand none\n", &[]).needs_openssh());
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(expand_tokens("%h-%r", "host", Some("user")), "host-user");
+        assert_eq!(expand_tokens("100%%", "host", None), "100%");
+        assert_eq!(expand_tokens("%x%", "host", None), "%x%");
+    }
+
+    #[test]
+    fn parse_ssh_urls() {
+        let url = SshUrl::parse("ssh://git@github.com:2222/owner/repo.git").unwrap();
+        assert_eq!((url.user.as_deref(), url.host.as_str(), url.port, url.path.as_str()), (Some("git"), "github.com", Some(2222), "/owner/repo.git"));
+
+        let url = SshUrl::parse("ssh://[::1]:22/repo").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("::1", Some(22), "/repo"));
+
+        let url = SshUrl::parse("git+ssh://host/repo").unwrap();
+        assert_eq!((url.user, url.host.as_str(), url.port), (None, "host", None));
+
+        let url = SshUrl::parse("git@github.com:owner/repo.git").unwrap();
+        assert_eq!((url.user.as_deref(), url.host.as_str(), url.path.as_str()), (Some("git"), "github.com", "owner/repo.git"));
+
+        let url = SshUrl::parse("[fe80::1]:repo").unwrap();
+        assert_eq!((url.host.as_str(), url.path.as_str()), ("fe80::1", "repo"));
+
+        assert!(SshUrl::parse("https://github.com/owner/repo.git").is_none());
+        assert!(SshUrl::parse("/srv/git/repo.git").is_none());
+        assert!(SshUrl::parse("./dir:with/colon").is_none());
+        assert!(SshUrl::parse("ssh://host:port/repo").is_none());
+    }
+
+    #[test]
+    fn resolve_ssh_urls() {
+        let host = |hostname: &str, port: Option<u16>, user: Option<&str>| SshHost {
+            hostname: String::from(hostname),
+            port,
+            user: user.map(String::from),
+            ..Default::default()
+        };
+
+        let scp = SshUrl::parse("gh:owner/repo.git").unwrap();
+        assert_eq!(scp.resolve(&host("github.com", None, Some("git"))).as_deref(), Some("git@github.com:owner/repo.git"));
+        assert_eq!(scp.resolve(&host("github.com", Some(22), None)).as_deref(), Some("github.com:owner/repo.git"));
+        // a relative path can't be combined with a port
+        assert_eq!(scp.resolve(&host("github.com", Some(2222), None)), None);
+
+        let home = SshUrl::parse("alice@box:~/repo").unwrap();
+        assert_eq!(home.resolve(&host("10.0.0.1", Some(2222), Some("bob"))).as_deref(), Some("ssh://alice@10.0.0.1:2222/~/repo"));
+
+        let url = SshUrl::parse("ssh://box/srv/repo").unwrap();
+        assert_eq!(url.resolve(&host("::1", Some(2222), None)).as_deref(), Some("ssh://[::1]:2222/srv/repo"));
+        assert_eq!(url.resolve(&host("box.lan", None, None)).as_deref(), Some("ssh://box.lan/srv/repo"));
+
+        // a port in the URL wins over the configured one
+        let url = SshUrl::parse("ssh://box:22/repo").unwrap();
+        assert_eq!(url.resolve(&host("box.lan", Some(2222), None)).as_deref(), Some("ssh://box.lan/repo"));
+    }
+}